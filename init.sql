@@ -1,3 +1,7 @@
+DROP TABLE IF EXISTS LangLinks;
+DROP TABLE IF EXISTS BuggedPages;
+DROP TABLE IF EXISTS Categories;
+DROP TABLE IF EXISTS CrawlState;
 DROP TABLE IF EXISTS Links;
 DROP TABLE IF EXISTS Alias;
 DROP TABLE IF EXISTS Pages;
@@ -7,7 +11,18 @@ CREATE TABLE IF NOT EXISTS Pages (
   title VARCHAR(255) COLLATE utf8mb4_bin UNIQUE NOT NULL,
   explored BOOLEAN DEFAULT false,
   bugged BOOLEAN DEFAULT false,
+  bugged_at TIMESTAMP NULL DEFAULT NULL,
+  degree INT UNSIGNED DEFAULT NULL,
+  pagerank DOUBLE DEFAULT NULL,
+  created_at DATETIME NULL DEFAULT NULL,
+  explored_at DATETIME NULL DEFAULT NULL,
+  claimed_by VARCHAR(64) NULL DEFAULT NULL,
+  claimed_at DATETIME NULL DEFAULT NULL,
+  wikidata_id VARCHAR(16) NULL DEFAULT NULL,
+  summary TEXT NULL DEFAULT NULL,
   KEY id_index (id),
+  KEY wikidata_index (wikidata_id),
+  KEY claimed_by_index (claimed_by),
   FULLTEXT KEY title_fulltext (title),
   PRIMARY KEY (id)
 );
@@ -25,12 +40,51 @@ CREATE TABLE IF NOT EXISTS Links (
   linker INT UNSIGNED NOT NULL,
   linked INT UNSIGNED NOT NULL,
   display VARCHAR(255) COLLATE utf8mb4_bin NOT NULL,
+  weight INT UNSIGNED DEFAULT NULL,
   KEY linker_index (linker),
   KEY linked_index (linked),
+  UNIQUE KEY linker_linked_unique (linker, linked),
   FULLTEXT KEY display_fulltext (display),
   FOREIGN KEY linker_foreign (linker) REFERENCES Pages(id),
   FOREIGN KEY linked_foreign (linked) REFERENCES Pages(id),
   PRIMARY KEY (linker, linked, display)
 );
 
-INSERT INTO Pages (id, title) VALUES (1095, "France");
+CREATE TABLE IF NOT EXISTS CrawlState (
+  run_id VARCHAR(64) NOT NULL,
+  explored BIGINT UNSIGNED NOT NULL,
+  bugged BIGINT UNSIGNED NOT NULL,
+  pages BIGINT UNSIGNED NOT NULL,
+  links BIGINT UNSIGNED NOT NULL,
+  started_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (run_id)
+);
+
+CREATE TABLE IF NOT EXISTS Categories (
+  id INT UNSIGNED NOT NULL,
+  category VARCHAR(255) COLLATE utf8mb4_bin NOT NULL,
+  KEY category_index (category),
+  FOREIGN KEY id_foreign (id) REFERENCES Pages(id),
+  PRIMARY KEY (id, category)
+);
+
+CREATE TABLE IF NOT EXISTS BuggedPages (
+  id INT UNSIGNED NOT NULL,
+  error TEXT NOT NULL,
+  failures INT UNSIGNED NOT NULL DEFAULT 1,
+  failed_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  FOREIGN KEY id_foreign (id) REFERENCES Pages(id),
+  PRIMARY KEY (id)
+);
+
+CREATE TABLE IF NOT EXISTS LangLinks (
+  id INT UNSIGNED NOT NULL,
+  lang VARCHAR(32) NOT NULL,
+  title VARCHAR(255) COLLATE utf8mb4_bin NOT NULL,
+  KEY lang_title_index (lang, title),
+  FOREIGN KEY id_foreign (id) REFERENCES Pages(id),
+  PRIMARY KEY (id, lang)
+);
+
+INSERT INTO Pages (id, title, created_at) VALUES (1095, "France", NOW());
@@ -0,0 +1,129 @@
+use crate::schema::ensure_schema;
+use crate::*;
+
+use mysql::{prelude::Queryable, PooledConn};
+use std::{collections::HashMap, error::Error};
+
+const SHOWN_COMPONENTS: usize = 10;
+
+// the weakly connected components, the direction of the links being ignored
+pub fn setup_components(connection: &mut PooledConn) {
+    if let Err(error) = ensure_schema(connection) {
+        println!("Couldn't create the database schema: {}", error);
+        return;
+    }
+
+    match components(connection) {
+        Ok(Components {
+            pages,
+            sizes,
+            largest,
+        }) => {
+            let single_pages = sizes.iter().filter(|size| **size == 1).count();
+            println!(
+                "\n{} pages in {} components, {} of them being a single page without links",
+                pages,
+                sizes.len(),
+                single_pages
+            );
+            println!("\nlargest components:");
+            println!("{:>10} {:>8}  page", "pages", "share");
+            for (size, page) in largest {
+                println!(
+                    "{:>10} {:>7.2}%  {}",
+                    size,
+                    size as f64 * 100.0 / pages as f64,
+                    page
+                );
+            }
+        }
+        Err(error) => println!("Error: {}", error),
+    }
+}
+
+struct Components {
+    pages: usize,
+    // the size of every component, the largest first
+    sizes: Vec<usize>,
+    // the largest components with one of their pages
+    largest: Vec<(usize, Page)>,
+}
+
+// union-find over the pages, the smaller set joining the larger one
+struct DisjointSets {
+    parent: Vec<u32>,
+    size: Vec<u32>,
+}
+
+impl DisjointSets {
+    fn new(count: usize) -> DisjointSets {
+        DisjointSets {
+            parent: (0..count as u32).collect(),
+            size: vec![1; count],
+        }
+    }
+
+    fn find(&mut self, mut index: usize) -> usize {
+        while self.parent[index] as usize != index {
+            // path halving keeps the trees flat without recursion
+            self.parent[index] = self.parent[self.parent[index] as usize];
+            index = self.parent[index] as usize;
+        }
+        index
+    }
+
+    fn union(&mut self, first: usize, second: usize) {
+        let (first, second) = (self.find(first), self.find(second));
+        if first == second {
+            return;
+        }
+        let (larger, smaller) = if self.size[first] >= self.size[second] {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        self.parent[smaller] = larger as u32;
+        self.size[larger] += self.size[smaller];
+    }
+}
+
+fn components(connection: &mut PooledConn) -> Result<Components, Box<dyn Error>> {
+    println!("loading the pages");
+    let query = "SELECT id FROM Pages ORDER BY id;";
+    let ids = timed(query, || connection.query_map(query, |id: usize| id))?;
+    if ids.is_empty() {
+        return Err(Box::from("No pages in the database"));
+    }
+    let index_of = ids
+        .iter()
+        .enumerate()
+        .map(|(index, id)| (*id, index))
+        .collect::<HashMap<usize, usize>>();
+
+    let mut sets = DisjointSets::new(ids.len());
+    println!("reading the links of {} pages", ids.len());
+    read_links(connection, &ids, &index_of, |linker, linked| {
+        sets.union(linker, linked)
+    })?;
+
+    let mut roots = (0..ids.len())
+        .filter(|index| sets.parent[*index] as usize == *index)
+        .map(|root| (sets.size[root] as usize, root))
+        .collect::<Vec<(usize, usize)>>();
+    roots.sort_unstable_by(|first, second| second.cmp(first));
+    let sizes = roots.iter().map(|(size, _)| *size).collect::<Vec<usize>>();
+
+    let mut largest = Vec::new();
+    for (size, root) in roots.into_iter().take(SHOWN_COMPONENTS) {
+        let query = "SELECT id, title FROM Pages WHERE id = ?;";
+        let page = timed(query, || connection.exec_first(query, (ids[root],)))?
+            .map(|(id, title): (usize, String)| Page { id, title })
+            .ok_or(format!("Page {} disappeared from the database", ids[root]))?;
+        largest.push((size, page));
+    }
+    Ok(Components {
+        pages: ids.len(),
+        sizes,
+        largest,
+    })
+}
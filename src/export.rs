@@ -0,0 +1,349 @@
+use crate::schema::ensure_schema;
+use crate::wikipath::get_page;
+use crate::*;
+
+use mysql::{params, prelude::Queryable, PooledConn};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+const NEIGHBOURS_CHUNK: usize = 8192;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    GraphMl,
+    Dot,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Option<ExportFormat> {
+        match format.trim().to_ascii_lowercase().as_str() {
+            "graphml" => Some(ExportFormat::GraphMl),
+            "dot" => Some(ExportFormat::Dot),
+            "json" => Some(ExportFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+pub fn setup_export(connection: &mut PooledConn, format: Option<&String>, path: Option<&String>) {
+    if let Err(error) = ensure_schema(connection) {
+        println!("Couldn't create the database schema: {}", error);
+        return;
+    }
+
+    let format = match format {
+        Some(format) => format.clone(),
+        None => ask("\nWhich format do you want to export to ? (graphml, dot or json) \n-> "),
+    };
+    let format = match ExportFormat::parse(&format) {
+        Some(format) => format,
+        None => {
+            println!("Unknown export format \"{}\"", format.trim());
+            return;
+        }
+    };
+    let path = match path {
+        Some(path) => path.clone(),
+        None => ask("\nWhere do you want to write the graph ? \n-> "),
+    };
+
+    println!("exporting the graph to {}", path.trim());
+    match export(connection, format, path.trim()) {
+        Ok((pages, links)) => println!("exported {} pages and {} links", pages, links),
+        Err(error) => println!("Error: {}", error),
+    }
+}
+
+// rows are streamed from the database straight into the file, the tables can be too big for memory
+fn export(
+    connection: &mut PooledConn,
+    format: ExportFormat,
+    path: &str,
+) -> Result<(usize, usize), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    write_header(&mut writer, format)?;
+    let mut pages = 0;
+    // the rows are written while they arrive, only the time until the first one is logged
+    let query = "SELECT id, title FROM Pages;";
+    for row in timed(query, || connection.query_iter(query))? {
+        let (id, title): (usize, String) = mysql::from_row(row?);
+        write_page(&mut writer, format, pages == 0, id, &title)?;
+        pages += 1;
+    }
+
+    write_links_start(&mut writer, format)?;
+    let mut links = 0;
+    let query = "SELECT linker, linked, display FROM Links;";
+    for row in timed(query, || connection.query_iter(query))? {
+        let (linker, linked, display): (usize, usize, String) = mysql::from_row(row?);
+        write_link(&mut writer, format, links == 0, linker, linked, &display)?;
+        links += 1;
+    }
+    write_footer(&mut writer, format)?;
+    writer.flush()?;
+
+    Ok((pages, links))
+}
+
+// the subgraph of the links from and to a page, and from and to its neighbours with two hops
+pub async fn setup_neighborhood(
+    connection: &mut PooledConn,
+    wiki_config: &WikiConfig,
+    page: Option<&String>,
+    format: Option<&String>,
+    path: Option<&String>,
+    two_hops: bool,
+) {
+    if let Err(error) = ensure_schema(connection) {
+        println!("Couldn't create the database schema: {}", error);
+        return;
+    }
+
+    let page = match page {
+        Some(page) => page.clone(),
+        None => ask("\nWhich page do you want to export ? (enter page url or title) \n-> "),
+    };
+    let page = match get_page(connection, wiki_config, page.trim(), true).await {
+        Ok(page) => page,
+        Err(error) => {
+            println!("Error: {}", error);
+            return;
+        }
+    };
+    let format = match format {
+        Some(format) => format.clone(),
+        None => ask("\nWhich format do you want to export to ? (graphml, dot or json) \n-> "),
+    };
+    let format = match ExportFormat::parse(&format) {
+        Some(format) => format,
+        None => {
+            println!("Unknown export format \"{}\"", format.trim());
+            return;
+        }
+    };
+    let path = match path {
+        Some(path) => path.clone(),
+        None => ask("\nWhere do you want to write the subgraph ? \n-> "),
+    };
+
+    println!(
+        "exporting the neighborhood of Page {} to {}",
+        page,
+        path.trim()
+    );
+    match export_neighborhood(connection, &page, two_hops, format, path.trim()) {
+        Ok((pages, links)) => println!("exported {} pages and {} links", pages, links),
+        Err(error) => println!("Error: {}", error),
+    }
+}
+
+fn export_neighborhood(
+    connection: &mut PooledConn,
+    page: &Page,
+    two_hops: bool,
+    format: ExportFormat,
+    path: &str,
+) -> Result<(usize, usize), Box<dyn Error>> {
+    let query = "SELECT linker, linked, display FROM Links WHERE linker = :id OR linked = :id;";
+    let mut links = timed(query, || {
+        connection.exec_map(
+            query,
+            params! { "id" => page.id },
+            |(linker, linked, display): (usize, usize, String)| ((linker, linked), display),
+        )
+    })?
+    .into_iter()
+    .collect::<HashMap<(usize, usize), String>>();
+
+    if two_hops {
+        let neighbours = links
+            .keys()
+            .flat_map(|(linker, linked)| [*linker, *linked])
+            .filter(|id| *id != page.id)
+            .collect::<HashSet<usize>>()
+            .into_iter()
+            .collect::<Vec<usize>>();
+        for chunk in neighbours.chunks(NEIGHBOURS_CHUNK) {
+            let ids = join_ids(chunk);
+            let query = format!(
+                "SELECT linker, linked, display FROM Links WHERE linker IN ({}) OR linked IN ({});",
+                ids, ids
+            );
+            links.extend(timed(&query, || {
+                connection.query_map(
+                    &query,
+                    |(linker, linked, display): (usize, usize, String)| ((linker, linked), display),
+                )
+            })?);
+        }
+    }
+
+    let ids = links
+        .keys()
+        .flat_map(|(linker, linked)| [*linker, *linked])
+        .chain([page.id])
+        .collect::<HashSet<usize>>()
+        .into_iter()
+        .collect::<Vec<usize>>();
+    let mut pages = Vec::new();
+    for chunk in ids.chunks(NEIGHBOURS_CHUNK) {
+        let query = format!(
+            "SELECT id, title FROM Pages WHERE id IN ({});",
+            join_ids(chunk)
+        );
+        pages.extend(timed(&query, || {
+            connection.query_map(&query, |(id, title): (usize, String)| (id, title))
+        })?);
+    }
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_header(&mut writer, format)?;
+    for (index, (id, title)) in pages.iter().enumerate() {
+        write_page(&mut writer, format, index == 0, *id, title)?;
+    }
+    write_links_start(&mut writer, format)?;
+    for (index, ((linker, linked), display)) in links.iter().enumerate() {
+        write_link(&mut writer, format, index == 0, *linker, *linked, display)?;
+    }
+    write_footer(&mut writer, format)?;
+    writer.flush()?;
+
+    Ok((pages.len(), links.len()))
+}
+
+fn join_ids(ids: &[usize]) -> String {
+    ids.iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+// json needs a comma before every element but the first of its array
+fn write_header(writer: &mut impl Write, format: ExportFormat) -> std::io::Result<()> {
+    match format {
+        ExportFormat::GraphMl => {
+            writeln!(writer, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+            writeln!(
+                writer,
+                "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">"
+            )?;
+            writeln!(
+                writer,
+                "  <key id=\"label\" for=\"node\" attr.name=\"label\" attr.type=\"string\"/>"
+            )?;
+            writeln!(
+                writer,
+                "  <key id=\"display\" for=\"edge\" attr.name=\"display\" attr.type=\"string\"/>"
+            )?;
+            writeln!(
+                writer,
+                "  <graph id=\"wikicrawl\" edgedefault=\"directed\">"
+            )
+        }
+        ExportFormat::Dot => writeln!(writer, "digraph wikicrawl {{"),
+        ExportFormat::Json => writeln!(writer, "{{\"pages\":["),
+    }
+}
+
+fn write_page(
+    writer: &mut impl Write,
+    format: ExportFormat,
+    first: bool,
+    id: usize,
+    title: &str,
+) -> std::io::Result<()> {
+    match format {
+        ExportFormat::GraphMl => writeln!(
+            writer,
+            "    <node id=\"n{}\"><data key=\"label\">{}</data></node>",
+            id,
+            escape_xml(title)
+        ),
+        ExportFormat::Dot => writeln!(writer, "  {} [label=\"{}\"];", id, escape_dot(title)),
+        ExportFormat::Json => writeln!(
+            writer,
+            "{}{}",
+            if first { "" } else { "," },
+            serde_json::json!({ "id": id, "title": title })
+        ),
+    }
+}
+
+fn write_links_start(writer: &mut impl Write, format: ExportFormat) -> std::io::Result<()> {
+    match format {
+        ExportFormat::Json => writeln!(writer, "],\"links\":["),
+        _ => Ok(()),
+    }
+}
+
+fn write_link(
+    writer: &mut impl Write,
+    format: ExportFormat,
+    first: bool,
+    linker: usize,
+    linked: usize,
+    display: &str,
+) -> std::io::Result<()> {
+    match format {
+        ExportFormat::GraphMl => writeln!(
+            writer,
+            "    <edge source=\"n{}\" target=\"n{}\"><data key=\"display\">{}</data></edge>",
+            linker,
+            linked,
+            escape_xml(display)
+        ),
+        ExportFormat::Dot => writeln!(
+            writer,
+            "  {} -> {} [label=\"{}\"];",
+            linker,
+            linked,
+            escape_dot(display)
+        ),
+        ExportFormat::Json => writeln!(
+            writer,
+            "{}{}",
+            if first { "" } else { "," },
+            serde_json::json!({ "linker": linker, "linked": linked, "display": display })
+        ),
+    }
+}
+
+fn write_footer(writer: &mut impl Write, format: ExportFormat) -> std::io::Result<()> {
+    match format {
+        ExportFormat::GraphMl => {
+            writeln!(writer, "  </graph>")?;
+            writeln!(writer, "</graphml>")
+        }
+        ExportFormat::Dot => writeln!(writer, "}}"),
+        ExportFormat::Json => writeln!(writer, "]}}"),
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.chars()
+        .map(|char| match char {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&apos;".to_string(),
+            _ => char.to_string(),
+        })
+        .collect()
+}
+
+fn escape_dot(text: &str) -> String {
+    text.chars()
+        .map(|char| match char {
+            '\\' => "\\\\".to_string(),
+            '"' => "\\\"".to_string(),
+            _ => char.to_string(),
+        })
+        .collect()
+}
@@ -1,41 +1,106 @@
-pub mod wikicrawl;
-use wikicrawl::setup_wikicrawl;
-
-pub mod wikipath;
-use wikipath::setup_wikipath;
-
-use mysql::Pool;
+use lib::components::setup_components;
+use lib::export::{setup_export, setup_neighborhood};
+use lib::pagerank::setup_pagerank;
+use lib::schema::missing_tables;
+use lib::verify::{setup_dedupe, setup_delete_page, setup_verify};
+use lib::wikicrawl::{setup_diagnostics, setup_requeue, setup_stats, setup_wikicrawl};
+use lib::wikipath::{setup_ancestors, setup_search, setup_wikipath, SearchOptions};
+use lib::{
+    configure_client, setup_sql_logs, Config, CrawlConfig, WikiConfig, WikiFetcher, CLIENT,
+    RATE_LIMITER,
+};
+use mysql::{Opts, Pool};
 use std::{
-    collections::HashMap,
     env,
-    io::{stdin, stdout, Error, ErrorKind, Write},
-    usize,
+    io::{stdin, stdout, Write},
 };
 
-const ENV_PATH: &str = ".env";
-const ENV_DEFAULT: &str =
-    "WIKICRAWL_USER=root\nWIKICRAWL_PASSWORD=root\nWIKICRAWL_HOST=localhost\nWIKICRAWL_PORT=3306\nWIKICRAWL_EXPLORING_PAGES=10\nWIKICRAWL_NEW_PAGES=80\n";
-
 #[tokio::main]
 async fn main() {
     std::env::set_var("RUST_BACKTRACE", "1");
-    let args: Vec<String> = env::args().collect();
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().partition(|arg| arg.starts_with("--"));
+    let json_output = flags.iter().any(|flag| flag == "--json");
+    let delete = flags.iter().any(|flag| flag == "--delete");
+    let quiet = flags.iter().any(|flag| flag == "--quiet");
+    let verbose_sql = flags.iter().any(|flag| flag == "--verbose-sql");
+    let heuristic = flags.iter().any(|flag| flag == "--heuristic");
+    let explored_only = flags.iter().any(|flag| flag == "--explored-only");
+    let two_hops = flags.iter().any(|flag| flag == "--two-hops");
+    let selftest = flags.iter().any(|flag| flag == "--selftest");
+    let list_pages = flags.iter().any(|flag| flag == "--list");
+    let max_paths = match flags.iter().find_map(|flag| flag.strip_prefix("--paths=")) {
+        Some(max_paths) => match max_paths.parse::<usize>() {
+            Ok(max_paths) if max_paths > 0 => max_paths,
+            _ => {
+                eprintln!("Error: invalid value \"{}\" for --paths", max_paths);
+                return;
+            }
+        },
+        None => 1,
+    };
+    // --from= and --to= take precedence over the trailing arguments of the wikipath command
+    let from = flags
+        .iter()
+        .find_map(|flag| flag.strip_prefix("--from="))
+        .map(str::to_string)
+        .or_else(|| args.get(2).cloned());
+    let to = flags
+        .iter()
+        .find_map(|flag| flag.strip_prefix("--to="))
+        .map(str::to_string)
+        .or_else(|| args.get(3).cloned());
     let mut command_line_argument = args
         .get(1)
         .unwrap_or(&"0".to_string())
         .parse::<usize>()
         .unwrap_or(0);
 
-    let (database_url, max_exploring_pages, max_new_pages) = get_env().unwrap();
+    let Config {
+        database: database_opts,
+        search_database: search_opts,
+        crawl: mut crawl_config,
+        wiki: mut wiki_config,
+    } = match Config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            if selftest {
+                std::process::exit(1);
+            }
+            return;
+        }
+    };
+    crawl_config.quiet |= quiet;
+    crawl_config.verbose_sql |= verbose_sql;
+    if !wiki_config.user_agent.contains('@') && !wiki_config.user_agent.contains("://") {
+        eprintln!(
+            "\nWARNING: the user agent \"{}\" has no contact, wikimedia may block the requests\nset WIKICRAWL_USER_AGENT in the .env file, or user_agent in wikicrawl.json, to something like \"wikicrawl (you@example.org)\"\n",
+            wiki_config.user_agent
+        );
+    }
+    RATE_LIMITER.set_max_rps(wiki_config.max_rps);
+    if let Err(error) = configure_client(&wiki_config) {
+        eprintln!("Error: {}", error);
+        if selftest {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if selftest {
+        let passed = run_selftest(database_opts, &crawl_config, &wiki_config).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
 
-    println!("connecting to database");
-    let pool = Pool::new(database_url.clone().as_str()).unwrap();
+    eprintln!("connecting to database");
+    let pool = Pool::new(database_opts).unwrap();
     let mut connection = pool.get_conn().unwrap();
 
     let mut user_input = String::new();
     loop {
         if command_line_argument == 0 {
-            print!("\nWhat do you want to do ?\n1: Search the smallest path between two pages\n2: Crawl wikipedia\n3: Exit\nYou Choose: ");
+            print!("\nWhat do you want to do ?\n1: Search the smallest path between two pages\n2: Crawl wikipedia\n3: Export the graph\n4: Show crawl statistics\n5: Compute the pagerank of the pages\n6: Find dead end and orphan pages\n7: Requeue the bugged pages\n8: Verify the integrity of the graph\n9: Export the neighborhood of a page\n10: Delete a page and its links\n11: Merge the duplicated pages\n12: Search the pages by the start of their title\n13: List the pages reaching a page\n14: Show the connected components of the graph\n15: Exit\nYou Choose: ");
             stdout().flush().unwrap();
             user_input.clear();
             stdin()
@@ -45,10 +110,81 @@ async fn main() {
             user_input = command_line_argument.to_string();
             command_line_argument = 0;
         }
-        match user_input.trim().parse::<usize>() {
-            Ok(1) => setup_wikipath(&mut connection).await,
-            Ok(2) => setup_wikicrawl(&mut connection, max_exploring_pages, max_new_pages).await,
-            Ok(3) => println!("Exiting the program"),
+        let choice = user_input.trim().parse::<usize>();
+        // the crawl logs its queries with the rest of its logs
+        if crawl_config.verbose_sql
+            && matches!(choice, Ok(choice) if (1..=14).contains(&choice) && choice != 2)
+        {
+            setup_sql_logs();
+        }
+        match choice {
+            Ok(1) => {
+                prepare_wiki(&mut wiki_config, &crawl_config).await;
+                // the path search has its own connections, whose read timeout turns a stuck query into a retry
+                let search_pool = Pool::new(search_opts).unwrap();
+                let mut search_connection = search_pool.get_conn().unwrap();
+                let result = setup_wikipath(
+                    &search_pool,
+                    &mut search_connection,
+                    &wiki_config,
+                    (from.as_deref(), to.as_deref()),
+                    json_output,
+                    SearchOptions {
+                        max_paths,
+                        heuristic,
+                        explored_only,
+                        workers: crawl_config.search_workers,
+                        chunk_size: crawl_config.path_chunk,
+                    },
+                )
+                .await;
+                if let Err(error) = result {
+                    eprintln!("Error: {}", error);
+                }
+            }
+            Ok(2) => {
+                prepare_wiki(&mut wiki_config, &crawl_config).await;
+                let result =
+                    setup_wikicrawl(&pool, &mut connection, &crawl_config, &wiki_config).await;
+                if let Err(error) = result {
+                    eprintln!("Error: {}", error);
+                }
+            }
+            Ok(3) => setup_export(&mut connection, args.get(2), args.get(3)),
+            Ok(4) => setup_stats(&mut connection),
+            Ok(5) => setup_pagerank(&mut connection, args.get(2), args.get(3)),
+            Ok(6) => setup_diagnostics(&mut connection),
+            Ok(7) => setup_requeue(&mut connection, args.get(2), crawl_config.max_failures),
+            Ok(8) => setup_verify(&mut connection, delete),
+            Ok(9) => {
+                setup_neighborhood(
+                    &mut connection,
+                    &wiki_config,
+                    args.get(2),
+                    args.get(3),
+                    args.get(4),
+                    two_hops,
+                )
+                .await
+            }
+            Ok(10) => setup_delete_page(&mut connection, &wiki_config, args.get(2)).await,
+            Ok(11) => setup_dedupe(&mut connection),
+            Ok(12) => setup_search(&mut connection, args.get(2)),
+            Ok(13) => {
+                let search_pool = Pool::new(search_opts).unwrap();
+                let mut search_connection = search_pool.get_conn().unwrap();
+                setup_ancestors(
+                    &search_pool,
+                    &mut search_connection,
+                    &wiki_config,
+                    (args.get(2), args.get(3)),
+                    list_pages,
+                    crawl_config.path_chunk,
+                )
+                .await
+            }
+            Ok(14) => setup_components(&mut connection),
+            Ok(15) => println!("Exiting the program"),
             _ => {
                 println!("Please enter a valid number.");
                 continue;
@@ -58,67 +194,77 @@ async fn main() {
     }
 }
 
-fn get_env() -> Result<(String, usize, usize), Error> {
-    let env_read = std::fs::read_to_string(ENV_PATH);
-    if env_read.is_err() {
-        let env_write = std::fs::write(ENV_PATH, ENV_DEFAULT);
-        if env_write.is_err() {
-            return Err(Error::new(
-                ErrorKind::NotFound,
-                format!("Couldn't create .env file: {}", env_write.unwrap_err()),
-            ));
-        }
-        return Err(Error::new(
-            std::io::ErrorKind::NotFound,
-            format!(
-                "Error: .env file not found, created a new one with default values:\n{}",
-                ENV_DEFAULT
-            ),
-        ));
-    }
+// only the commands reaching wikipedia wait for the namespaces and the check of the regexes
+async fn prepare_wiki(wiki_config: &mut WikiConfig, crawl_config: &CrawlConfig) {
+    eprintln!("loading the namespaces of {}", wiki_config.host);
+    wiki_config.load_namespaces(&*CLIENT).await;
 
-    let env_content = env_read.unwrap();
-    if env_content.is_empty() {
-        return Err(Error::new(
-				ErrorKind::InvalidData,
-				format!("Error: .env file is empty, delete it to see default values or fill it with the following values:\n{}", ENV_DEFAULT),
-			));
+    eprintln!("checking the regexes against {}", crawl_config.seed_title);
+    if let Err(error) = wiki_config
+        .check_regexes(&crawl_config.seed_title, &*CLIENT)
+        .await
+    {
+        eprintln!(
+            "\nWARNING: the regexes don't work on {} anymore, the markup may have changed: {}\nset WIKICRAWL_API_REGEX and WIKICRAWL_WEB_REGEX in the .env file, or api_regex and web_regex in wikicrawl.json, to fix them\n",
+            wiki_config.host, error
+        );
     }
+}
 
-    let vars = env_content
-        .split("\n")
-        .filter_map(|line| line.split_once("="))
-        .map(|(str1, str2)| (str1.to_string().split_off(10), str2.to_string()))
-        .collect::<HashMap<String, String>>();
+// every check runs even after a failure, so that a single run shows everything to fix
+async fn run_selftest(
+    database_opts: Opts,
+    crawl_config: &CrawlConfig,
+    wiki_config: &WikiConfig,
+) -> bool {
+    let report = |check: &str, result: Result<(), String>| -> bool {
+        match result {
+            Ok(()) => println!("PASS  {}", check),
+            Err(ref error) => println!("FAIL  {}: {}", check, error),
+        }
+        result.is_ok()
+    };
+    let mut passed = true;
 
-    if !vars.contains_key("USER")
-        || !vars.contains_key("PASSWORD")
-        || !vars.contains_key("HOST")
-        || !vars.contains_key("PORT")
-        || !vars.contains_key("EXPLORING_PAGES")
-        || !vars.contains_key("NEW_PAGES")
-    {
-        return Err(std::io::Error::new(
-				std::io::ErrorKind::InvalidData,
-				format!("Error: .env file is missing some values, delete it to see default values or fill it with the following values:\n{}", ENV_DEFAULT),
-			));
-    }
+    let connection = Pool::new(database_opts)
+        .and_then(|pool| pool.get_conn())
+        .map_err(|error| error.to_string());
+    passed &= report(
+        "database reachable",
+        connection
+            .as_ref()
+            .map(|_| ())
+            .map_err(|error| error.clone()),
+    );
+    let schema = match connection {
+        Ok(mut connection) => match missing_tables(&mut connection) {
+            Ok(missing) if missing.is_empty() => Ok(()),
+            Ok(missing) => Err(format!("missing tables {}", missing.join(", "))),
+            Err(error) => Err(error.to_string()),
+        },
+        Err(_) => Err("no database connection".to_string()),
+    };
+    passed &= report("schema exists", schema);
 
-    let connection_url = if vars["PASSWORD"].is_empty() {
-        format!(
-            "mysql://{}@{}:{}/wikicrawl",
-            vars["USER"], vars["HOST"], vars["PORT"]
-        )
-    } else {
-        format!(
-            "mysql://{}:{}@{}:{}/wikicrawl",
-            vars["USER"], vars["PASSWORD"], vars["HOST"], vars["PORT"]
-        )
+    let request = format!(
+        "https://{}/w/api.php?action=query&format=json&meta=siteinfo",
+        wiki_config.host
+    );
+    let host = match CLIENT.fetch(&request).await {
+        Ok(body) if body.starts_with('{') => Ok(()),
+        Ok(_) => Err(format!("unexpected answer to {}", request)),
+        Err(error) => Err(error.to_string()),
     };
+    passed &= report(&format!("{} responds", wiki_config.host), host);
+
+    let regexes = wiki_config
+        .check_regexes(&crawl_config.seed_title, &*CLIENT)
+        .await
+        .map_err(|error| error.to_string());
+    passed &= report(
+        &format!("regexes match {}", crawl_config.seed_title),
+        regexes,
+    );
 
-    Ok((
-        connection_url,
-        vars["EXPLORING_PAGES"].parse::<usize>().unwrap_or(75),
-        vars["NEW_PAGES"].parse::<usize>().unwrap_or(80),
-    ))
+    passed
 }
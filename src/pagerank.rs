@@ -0,0 +1,182 @@
+use crate::schema::ensure_schema;
+use crate::*;
+
+use mysql::{prelude::Queryable, PooledConn};
+use std::{collections::HashMap, error::Error};
+
+const DEFAULT_DAMPING: f64 = 0.85;
+const DEFAULT_ITERATIONS: usize = 20;
+// above this many links, they are read again from the database at each iteration instead of kept in memory
+const MAX_IN_MEMORY_LINKS: usize = 50_000_000;
+const UPDATE_CHUNK: usize = 5000;
+
+pub fn setup_pagerank(
+    connection: &mut PooledConn,
+    damping: Option<&String>,
+    iterations: Option<&String>,
+) {
+    if let Err(error) = ensure_schema(connection) {
+        println!("Couldn't create the database schema: {}", error);
+        return;
+    }
+
+    let damping = match damping {
+        Some(damping) => damping.clone(),
+        None => ask(&format!(
+            "\nWhich damping factor do you want ? (default {}) \n-> ",
+            DEFAULT_DAMPING
+        )),
+    };
+    let damping = match damping.trim() {
+        "" => DEFAULT_DAMPING,
+        damping => match damping.parse::<f64>() {
+            Ok(damping) if (0.0..1.0).contains(&damping) => damping,
+            _ => {
+                println!("Invalid damping factor \"{}\"", damping);
+                return;
+            }
+        },
+    };
+    let iterations = match iterations {
+        Some(iterations) => iterations.clone(),
+        None => ask(&format!(
+            "\nHow many iterations do you want ? (default {}) \n-> ",
+            DEFAULT_ITERATIONS
+        )),
+    };
+    let iterations = match iterations.trim() {
+        "" => DEFAULT_ITERATIONS,
+        iterations => match iterations.parse::<usize>() {
+            Ok(iterations) if iterations > 0 => iterations,
+            _ => {
+                println!("Invalid iteration count \"{}\"", iterations);
+                return;
+            }
+        },
+    };
+
+    match pagerank(connection, damping, iterations) {
+        Ok(top_pages) => {
+            println!("\nmost important pages:");
+            println!("{:>4} {:>12} {:>10}  title", "rank", "score", "id");
+            for (rank, (page, score)) in top_pages.iter().enumerate() {
+                println!(
+                    "{:>4} {:>12.8} {:>10}  {}",
+                    rank + 1,
+                    score,
+                    page.id,
+                    page.title
+                );
+            }
+        }
+        Err(error) => println!("Error: {}", error),
+    }
+}
+
+fn pagerank(
+    connection: &mut PooledConn,
+    damping: f64,
+    iterations: usize,
+) -> Result<Vec<(Page, f64)>, Box<dyn Error>> {
+    println!("loading the pages");
+    let query = "SELECT id FROM Pages ORDER BY id;";
+    let ids = timed(query, || connection.query_map(query, |id: usize| id))?;
+    if ids.is_empty() {
+        return Err(Box::from("No pages in the database"));
+    }
+    let index_of = ids
+        .iter()
+        .enumerate()
+        .map(|(index, id)| (*id, index))
+        .collect::<HashMap<usize, usize>>();
+
+    let query = "SELECT COUNT(*) FROM Links;";
+    let links_count: usize = timed(query, || connection.query_first(query))?.unwrap_or(0);
+    let in_memory = links_count <= MAX_IN_MEMORY_LINKS;
+    println!(
+        "{} pages and {} links, {}",
+        ids.len(),
+        links_count,
+        if in_memory {
+            "keeping the links in memory"
+        } else {
+            "reading the links at each iteration"
+        }
+    );
+
+    // the out-degree is counted while reading the links the first time
+    let mut out_degree = vec![0_usize; ids.len()];
+    let mut links: Vec<(u32, u32)> = Vec::new();
+    read_links(connection, &ids, &index_of, |linker, linked| {
+        out_degree[linker] += 1;
+        if in_memory {
+            links.push((linker as u32, linked as u32));
+        }
+    })?;
+
+    let pages_count = ids.len() as f64;
+    let mut rank = vec![1.0 / pages_count; ids.len()];
+    for iteration in 1..=iterations {
+        // the rank of pages without links is spread over every page
+        let dangling = rank
+            .iter()
+            .zip(out_degree.iter())
+            .filter(|(_, degree)| **degree == 0)
+            .map(|(rank, _)| rank)
+            .sum::<f64>();
+        let base = (1.0 - damping) / pages_count + damping * dangling / pages_count;
+        let mut next_rank = vec![base; ids.len()];
+
+        if in_memory {
+            for (linker, linked) in links.iter() {
+                let (linker, linked) = (*linker as usize, *linked as usize);
+                next_rank[linked] += damping * rank[linker] / out_degree[linker] as f64;
+            }
+        } else {
+            read_links(connection, &ids, &index_of, |linker, linked| {
+                next_rank[linked] += damping * rank[linker] / out_degree[linker] as f64;
+            })?;
+        }
+
+        let delta = rank
+            .iter()
+            .zip(next_rank.iter())
+            .map(|(old, new)| (old - new).abs())
+            .sum::<f64>();
+        rank = next_rank;
+        println!(
+            "iteration {}/{} (delta {:.3e})",
+            iteration, iterations, delta
+        );
+    }
+
+    println!("saving the scores");
+    for chunk in ids
+        .iter()
+        .zip(rank.iter())
+        .collect::<Vec<_>>()
+        .chunks(UPDATE_CHUNK)
+    {
+        let query = format!(
+            "UPDATE Pages SET pagerank = CASE id {} END WHERE id IN ({});",
+            chunk
+                .iter()
+                .map(|(id, score)| format!("WHEN {} THEN {:e}", id, score))
+                .collect::<Vec<String>>()
+                .join(" "),
+            chunk
+                .iter()
+                .map(|(id, _)| id.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        );
+        timed(&query, || connection.query_drop(&query))?;
+    }
+
+    let query = "SELECT id, title, pagerank FROM Pages ORDER BY pagerank DESC LIMIT 20;";
+    Ok(timed(query, || {
+        connection.query_map(query, |(id, title, score): (usize, String, f64)| {
+            (Page { id, title }, score)
+        })
+    })?)
+}
@@ -0,0 +1,259 @@
+use mysql::{prelude::Queryable, PooledConn};
+
+// Pages(id, title, original_title, explored, bugged, bugged_at, degree, pagerank, created_at, explored_at,
+// claimed_by, claimed_at, wikidata_id, summary): every page found, under its lowercased title and its title
+// in the case of wikipedia, explored once its links are listed, degree being its number of distinct
+// linked pages, pagerank its score from the last pagerank command, the case of its title and the dates
+// of its discovery and last exploration NULL for older rows, the run exploring it with the date of its
+// claim, and its wikidata item and summary when WIKICRAWL_WIKIDATA and WIKICRAWL_FETCH_SUMMARIES were
+// set at its discovery
+const CREATE_PAGES: &str = "
+CREATE TABLE IF NOT EXISTS Pages (
+  id INT UNSIGNED UNIQUE NOT NULL,
+  title VARCHAR(255) COLLATE utf8mb4_bin UNIQUE NOT NULL,
+  original_title VARCHAR(255) COLLATE utf8mb4_bin NULL DEFAULT NULL,
+  explored BOOLEAN DEFAULT false,
+  bugged BOOLEAN DEFAULT false,
+  bugged_at TIMESTAMP NULL DEFAULT NULL,
+  degree INT UNSIGNED DEFAULT NULL,
+  pagerank DOUBLE DEFAULT NULL,
+  created_at DATETIME NULL DEFAULT NULL,
+  explored_at DATETIME NULL DEFAULT NULL,
+  claimed_by VARCHAR(64) NULL DEFAULT NULL,
+  claimed_at DATETIME NULL DEFAULT NULL,
+  wikidata_id VARCHAR(16) NULL DEFAULT NULL,
+  summary TEXT NULL DEFAULT NULL,
+  KEY id_index (id),
+  KEY wikidata_index (wikidata_id),
+  KEY claimed_by_index (claimed_by),
+  KEY original_title_index (original_title),
+  FULLTEXT KEY title_fulltext (title),
+  PRIMARY KEY (id)
+);";
+
+// Alias(alias, id): every link text that resolved to a page
+const CREATE_ALIAS: &str = "
+CREATE TABLE IF NOT EXISTS Alias (
+  alias VARCHAR(255) COLLATE utf8mb4_bin UNIQUE NOT NULL,
+  id INT UNSIGNED NOT NULL,
+  KEY id_index (id),
+  FULLTEXT KEY alias_fulltext (alias),
+  FOREIGN KEY id_foreign (id) REFERENCES Pages(id),
+  PRIMARY KEY (alias, id)
+);";
+
+// Links(linker, linked, display, weight): linker has a link to linked showing the display text, weight being
+// the number of distinct texts of its links to linked, NULL for the links of older runs
+const CREATE_LINKS: &str = "
+CREATE TABLE IF NOT EXISTS Links (
+  linker INT UNSIGNED NOT NULL,
+  linked INT UNSIGNED NOT NULL,
+  display VARCHAR(255) COLLATE utf8mb4_bin NOT NULL,
+  weight INT UNSIGNED DEFAULT NULL,
+  KEY linker_index (linker),
+  KEY linked_index (linked),
+  UNIQUE KEY linker_linked_unique (linker, linked),
+  FULLTEXT KEY display_fulltext (display),
+  FOREIGN KEY linker_foreign (linker) REFERENCES Pages(id),
+  FOREIGN KEY linked_foreign (linked) REFERENCES Pages(id),
+  PRIMARY KEY (linker, linked, display)
+);";
+
+// CrawlState(run_id, explored, bugged, pages, links, updated_at): the totals of each crawl run after its last batch
+const CREATE_CRAWL_STATE: &str = "
+CREATE TABLE IF NOT EXISTS CrawlState (
+  run_id VARCHAR(64) NOT NULL,
+  explored BIGINT UNSIGNED NOT NULL,
+  bugged BIGINT UNSIGNED NOT NULL,
+  pages BIGINT UNSIGNED NOT NULL,
+  links BIGINT UNSIGNED NOT NULL,
+  started_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (run_id)
+);";
+
+// Categories(id, category): the categories of every explored page, lowercased without their namespace
+const CREATE_CATEGORIES: &str = "
+CREATE TABLE IF NOT EXISTS Categories (
+  id INT UNSIGNED NOT NULL,
+  category VARCHAR(255) COLLATE utf8mb4_bin NOT NULL,
+  KEY category_index (category),
+  FOREIGN KEY id_foreign (id) REFERENCES Pages(id),
+  PRIMARY KEY (id, category)
+);";
+
+// BuggedPages(id, error, failures, failed_at): the last error of every page marked as bugged,
+// with the number of times it was marked and the date of the last one
+const CREATE_BUGGED_PAGES: &str = "
+CREATE TABLE IF NOT EXISTS BuggedPages (
+  id INT UNSIGNED NOT NULL,
+  error TEXT NOT NULL,
+  failures INT UNSIGNED NOT NULL DEFAULT 1,
+  failed_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  FOREIGN KEY id_foreign (id) REFERENCES Pages(id),
+  PRIMARY KEY (id)
+);";
+
+// LangLinks(id, lang, title): the lowercased title of the equivalent of every explored page on the wikipedia
+// of each other language, when WIKICRAWL_LANGLINKS was set at its exploration
+const CREATE_LANG_LINKS: &str = "
+CREATE TABLE IF NOT EXISTS LangLinks (
+  id INT UNSIGNED NOT NULL,
+  lang VARCHAR(32) NOT NULL,
+  title VARCHAR(255) COLLATE utf8mb4_bin NOT NULL,
+  KEY lang_title_index (lang, title),
+  FOREIGN KEY id_foreign (id) REFERENCES Pages(id),
+  PRIMARY KEY (id, lang)
+);";
+
+const TABLES: [&str; 7] = [
+    "Pages",
+    "Alias",
+    "Links",
+    "CrawlState",
+    "Categories",
+    "BuggedPages",
+    "LangLinks",
+];
+
+// the FULLTEXT keys of Pages and Alias are needed by the MATCH ... AGAINST of wikipath
+pub fn ensure_schema(connection: &mut PooledConn) -> Result<(), mysql::Error> {
+    connection.query_drop(CREATE_PAGES)?;
+    connection.query_drop(CREATE_ALIAS)?;
+    connection.query_drop(CREATE_LINKS)?;
+    connection.query_drop(CREATE_CRAWL_STATE)?;
+    connection.query_drop(CREATE_CATEGORIES)?;
+    connection.query_drop(CREATE_BUGGED_PAGES)?;
+    connection.query_drop(CREATE_LANG_LINKS)?;
+    ensure_column(
+        connection,
+        "Pages",
+        "bugged_at",
+        "TIMESTAMP NULL DEFAULT NULL",
+    )?;
+    ensure_column(connection, "Pages", "degree", "INT UNSIGNED DEFAULT NULL")?;
+    ensure_column(connection, "Pages", "pagerank", "DOUBLE DEFAULT NULL")?;
+    ensure_column(
+        connection,
+        "Pages",
+        "created_at",
+        "DATETIME NULL DEFAULT NULL",
+    )?;
+    ensure_column(
+        connection,
+        "Pages",
+        "explored_at",
+        "DATETIME NULL DEFAULT NULL",
+    )?;
+    ensure_column(
+        connection,
+        "Pages",
+        "claimed_by",
+        "VARCHAR(64) NULL DEFAULT NULL",
+    )?;
+    ensure_column(
+        connection,
+        "Pages",
+        "claimed_at",
+        "DATETIME NULL DEFAULT NULL",
+    )?;
+    ensure_index(connection, "Pages", "claimed_by_index", "claimed_by")?;
+    ensure_column(
+        connection,
+        "Pages",
+        "wikidata_id",
+        "VARCHAR(16) NULL DEFAULT NULL",
+    )?;
+    ensure_index(connection, "Pages", "wikidata_index", "wikidata_id")?;
+    ensure_column(connection, "Pages", "summary", "TEXT NULL DEFAULT NULL")?;
+    ensure_column(
+        connection,
+        "Pages",
+        "original_title",
+        "VARCHAR(255) COLLATE utf8mb4_bin NULL DEFAULT NULL",
+    )?;
+    ensure_index(
+        connection,
+        "Pages",
+        "original_title_index",
+        "original_title",
+    )?;
+    ensure_column(connection, "Links", "weight", "INT UNSIGNED DEFAULT NULL")?;
+    ensure_links_unique_index(connection)
+}
+
+// the tables that ensure_schema creates and that are absent from the database
+pub fn missing_tables(connection: &mut PooledConn) -> Result<Vec<&'static str>, mysql::Error> {
+    let mut missing = Vec::new();
+    for table in TABLES {
+        let table_count: usize = connection
+            .query_first(format!(
+                "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = '{}';",
+                table
+            ))?
+            .unwrap_or(0);
+        if table_count == 0 {
+            missing.push(table);
+        }
+    }
+    Ok(missing)
+}
+
+// CREATE TABLE IF NOT EXISTS leaves the tables of older databases untouched
+fn ensure_column(
+    connection: &mut PooledConn,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), mysql::Error> {
+    let column_count: usize = connection
+        .query_first(format!(
+            "SELECT COUNT(*) FROM information_schema.columns WHERE table_schema = DATABASE() AND table_name = '{}' AND column_name = '{}';",
+            table, column
+        ))?
+        .unwrap_or(0);
+    if column_count > 0 {
+        return Ok(());
+    }
+    connection.query_drop(format!(
+        "ALTER TABLE {} ADD COLUMN {} {};",
+        table, column, definition
+    ))
+}
+
+fn ensure_index(
+    connection: &mut PooledConn,
+    table: &str,
+    index: &str,
+    columns: &str,
+) -> Result<(), mysql::Error> {
+    let index_count: usize = connection
+        .query_first(format!(
+            "SELECT COUNT(*) FROM information_schema.statistics WHERE table_schema = DATABASE() AND table_name = '{}' AND index_name = '{}';",
+            table, index
+        ))?
+        .unwrap_or(0);
+    if index_count > 0 {
+        return Ok(());
+    }
+    connection.query_drop(format!(
+        "ALTER TABLE {} ADD KEY {} ({});",
+        table, index, columns
+    ))
+}
+
+// databases created before the upsert have duplicated (linker, linked) rows that must go before the index
+fn ensure_links_unique_index(connection: &mut PooledConn) -> Result<(), mysql::Error> {
+    let index_count: usize = connection
+        .query_first(
+            "SELECT COUNT(*) FROM information_schema.statistics WHERE table_schema = DATABASE() AND table_name = 'Links' AND index_name = 'linker_linked_unique';",
+        )?
+        .unwrap_or(0);
+    if index_count > 0 {
+        return Ok(());
+    }
+    connection.query_drop(
+        "DELETE duplicate FROM Links AS duplicate JOIN Links AS kept ON duplicate.linker = kept.linker AND duplicate.linked = kept.linked AND duplicate.display > kept.display;",
+    )?;
+    connection.query_drop("ALTER TABLE Links ADD UNIQUE KEY linker_linked_unique (linker, linked);")
+}
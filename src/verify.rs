@@ -0,0 +1,265 @@
+use crate::schema::ensure_schema;
+use crate::wikipath::get_page;
+use crate::*;
+
+use mysql::{params, prelude::Queryable, PooledConn, Transaction, TxOpts};
+
+// links left by crashes of databases without foreign keys, their ids are missing from Pages
+const DANGLING_LINKS: [(&str, &str); 2] = [
+    (
+        "links from a missing page",
+        "FROM Links LEFT JOIN Pages ON Pages.id = Links.linker WHERE Pages.id IS NULL",
+    ),
+    (
+        "links to a missing page",
+        "FROM Links LEFT JOIN Pages ON Pages.id = Links.linked WHERE Pages.id IS NULL",
+    ),
+];
+// the rows referencing a page go before the page itself because of the foreign keys
+const PAGE_ROWS: [(&str, &str); 6] = [
+    (
+        "Links",
+        "DELETE FROM Links WHERE linker = :id OR linked = :id;",
+    ),
+    ("Alias", "DELETE FROM Alias WHERE id = :id;"),
+    ("Categories", "DELETE FROM Categories WHERE id = :id;"),
+    ("BuggedPages", "DELETE FROM BuggedPages WHERE id = :id;"),
+    ("LangLinks", "DELETE FROM LangLinks WHERE id = :id;"),
+    ("Pages", "DELETE FROM Pages WHERE id = :id;"),
+];
+// pages stored twice under different ids, like before and after the titles were lowercased,
+// the explored one with the lowest id comes first and is the one kept
+const DUPLICATED_PAGES: &str = "SELECT GROUP_CONCAT(id ORDER BY explored DESC, id ASC) FROM Pages GROUP BY LOWER(title) HAVING COUNT(*) > 1;";
+// the rows of a duplicate are moved to the kept page, those it already has are deleted with the duplicate.
+// the links between the two pages and from the duplicate to itself go first, they would become self links of the kept page
+const MERGED_ROWS: [&str; 6] = [
+    "DELETE FROM Links WHERE (linker = :kept AND linked = :duplicate) OR (linker = :duplicate AND linked IN (:kept, :duplicate));",
+    "UPDATE IGNORE Links SET linker = :kept WHERE linker = :duplicate;",
+    "UPDATE IGNORE Links SET linked = :kept WHERE linked = :duplicate;",
+    "UPDATE IGNORE Alias SET id = :kept WHERE id = :duplicate;",
+    "UPDATE IGNORE Categories SET id = :kept WHERE id = :duplicate;",
+    "UPDATE IGNORE LangLinks SET id = :kept WHERE id = :duplicate;",
+];
+const EXPLORED_WITHOUT_LINKS: &str =
+    "FROM Pages LEFT JOIN Links ON Pages.id = Links.linker WHERE Links.linker IS NULL AND Pages.explored = TRUE";
+
+// nothing is deleted without the --delete flag and a confirmation
+pub fn setup_verify(connection: &mut PooledConn, delete: bool) {
+    if let Err(error) = ensure_schema(connection) {
+        println!("Couldn't create the database schema: {}", error);
+        return;
+    }
+
+    let mut dangling_count = 0;
+    for (name, from) in DANGLING_LINKS {
+        println!();
+        let query = format!("SELECT COUNT(*) {};", from);
+        let count = timed(&query, || connection.query_first(&query));
+        let query = format!(
+            "SELECT Links.linker, Links.linked, Links.display {} LIMIT 10;",
+            from
+        );
+        let sample = timed(&query, || {
+            connection.query_map(
+                &query,
+                |(linker, linked, display): (usize, usize, String)| (linker, linked, display),
+            )
+        });
+        match (count, sample) {
+            (Ok(count), Ok(sample)) => {
+                let count = count.unwrap_or(0_usize);
+                dangling_count += count;
+                println!("{}: {}", name, count);
+                for (linker, linked, display) in sample {
+                    println!("    {} -> {} by \"{}\"", linker, linked, display);
+                }
+            }
+            (Err(error), _) | (_, Err(error)) => {
+                println!("Error while looking for {}: {}", name, error);
+                return;
+            }
+        }
+    }
+
+    println!();
+    let query = format!("SELECT COUNT(*) {};", EXPLORED_WITHOUT_LINKS);
+    let count = timed(&query, || connection.query_first(&query));
+    let query = format!(
+        "SELECT Pages.id, Pages.title {} LIMIT 10;",
+        EXPLORED_WITHOUT_LINKS
+    );
+    let sample = timed(&query, || {
+        connection.query_map(&query, |(id, title): (usize, String)| Page { id, title })
+    });
+    match (count, sample) {
+        (Ok(count), Ok(sample)) => {
+            println!("explored pages without links: {}", count.unwrap_or(0_usize));
+            for page in sample {
+                println!("    Page {}", page);
+            }
+        }
+        (Err(error), _) | (_, Err(error)) => {
+            println!(
+                "Error while looking for explored pages without links: {}",
+                error
+            );
+            return;
+        }
+    }
+
+    if !delete || dangling_count == 0 {
+        return;
+    }
+    let confirmation = ask(&format!(
+        "\nDelete the {} dangling links ? (type yes to confirm) \n-> ",
+        dangling_count
+    ));
+    if confirmation.trim() != "yes" {
+        println!("Nothing deleted");
+        return;
+    }
+    let mut deleted = 0;
+    for (name, from) in DANGLING_LINKS {
+        let query = format!("DELETE Links {};", from);
+        if let Err(error) = timed(&query, || connection.query_drop(&query)) {
+            println!("Error while deleting the {}: {}", name, error);
+            return;
+        }
+        deleted += connection.affected_rows();
+    }
+    println!("deleted {} dangling links", deleted);
+}
+
+// removes a page crawled by mistake, like a vandalized article, after a confirmation
+pub async fn setup_delete_page(
+    connection: &mut PooledConn,
+    wiki_config: &WikiConfig,
+    page: Option<&String>,
+) {
+    if let Err(error) = ensure_schema(connection) {
+        println!("Couldn't create the database schema: {}", error);
+        return;
+    }
+
+    let page = match page {
+        Some(page) => page.clone(),
+        None => ask("\nWhich page do you want to delete ? (enter page url or title) \n-> "),
+    };
+    let page = match get_page(connection, wiki_config, page.trim(), true).await {
+        Ok(page) => page,
+        Err(error) => {
+            println!("Error: {}", error);
+            return;
+        }
+    };
+    let confirmation = ask(&format!(
+        "\nDelete Page {} with its links ? (type yes to confirm) \n-> ",
+        page
+    ));
+    if confirmation.trim() != "yes" {
+        println!("Nothing deleted");
+        return;
+    }
+
+    match delete_page(connection, page.id) {
+        Ok(deleted) => {
+            for (table, count) in deleted {
+                println!("deleted {} rows from {}", count, table);
+            }
+        }
+        Err(error) => println!("Error while deleting Page {}: {}", page, error),
+    }
+}
+
+// deletes the page and every row referencing it in a single transaction,
+// returns the number of rows deleted from each table
+pub fn delete_page(
+    connection: &mut PooledConn,
+    id: usize,
+) -> Result<Vec<(&'static str, u64)>, mysql::Error> {
+    let mut transaction = connection.start_transaction(TxOpts::default())?;
+    let deleted = delete_page_rows(&mut transaction, id)?;
+    transaction.commit()?;
+    Ok(deleted)
+}
+
+fn delete_page_rows(
+    transaction: &mut Transaction,
+    id: usize,
+) -> Result<Vec<(&'static str, u64)>, mysql::Error> {
+    let mut deleted = Vec::new();
+    for (table, query) in PAGE_ROWS {
+        timed(query, || {
+            transaction.exec_drop(query, params! { "id" => id })
+        })?;
+        deleted.push((table, transaction.affected_rows()));
+    }
+    Ok(deleted)
+}
+
+pub fn setup_dedupe(connection: &mut PooledConn) {
+    if let Err(error) = ensure_schema(connection) {
+        println!("Couldn't create the database schema: {}", error);
+        return;
+    }
+
+    let duplicated_pages = match timed(DUPLICATED_PAGES, || {
+        connection.query_map(DUPLICATED_PAGES, |ids: String| {
+            ids.split(',')
+                .filter_map(|id| id.parse::<usize>().ok())
+                .collect::<Vec<usize>>()
+        })
+    }) {
+        Ok(duplicated_pages) => duplicated_pages,
+        Err(error) => {
+            println!("Error while looking for duplicated pages: {}", error);
+            return;
+        }
+    };
+    if duplicated_pages.is_empty() {
+        println!("No duplicated pages found");
+        return;
+    }
+    for ids in duplicated_pages.iter().take(10) {
+        println!("    Page {} kept, duplicated by {:?}", ids[0], &ids[1..]);
+    }
+    let confirmation = ask(&format!(
+        "\nMerge the {} duplicated pages ? (type yes to confirm) \n-> ",
+        duplicated_pages.len()
+    ));
+    if confirmation.trim() != "yes" {
+        println!("Nothing merged");
+        return;
+    }
+
+    let mut merged = 0;
+    for ids in duplicated_pages {
+        match merge_pages(connection, ids[0], &ids[1..]) {
+            Ok(()) => merged += ids.len() - 1,
+            Err(error) => {
+                println!("Error while merging the pages {:?}: {}", ids, error);
+                break;
+            }
+        }
+    }
+    println!("merged {} pages", merged);
+}
+
+// moves the links, aliases and categories of the duplicates to the kept page then deletes them,
+// in a single transaction so that a page is never left half merged
+pub fn merge_pages(
+    connection: &mut PooledConn,
+    kept: usize,
+    duplicates: &[usize],
+) -> Result<(), mysql::Error> {
+    let mut transaction = connection.start_transaction(TxOpts::default())?;
+    for duplicate in duplicates {
+        for query in MERGED_ROWS {
+            timed(query, || {
+                transaction.exec_drop(query, params! { "kept" => kept, "duplicate" => duplicate })
+            })?;
+        }
+        delete_page_rows(&mut transaction, *duplicate)?;
+    }
+    transaction.commit()
+}
@@ -1,67 +1,85 @@
-use lib::*;
+use crate::schema::ensure_schema;
+use crate::*;
 
-use chrono::Local;
-use log::{error, info, warn, LevelFilter};
+use chrono::{Local, NaiveDate};
+use futures::stream::{self, StreamExt};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{error, info, warn, LevelFilter, Record};
 use log4rs::append::console::{ConsoleAppender, Target};
 use log4rs::append::file::FileAppender;
-use log4rs::config::{Appender, Root};
-use log4rs::encode::pattern::PatternEncoder;
-use log4rs::Config;
-use mysql::{prelude::*, PooledConn};
-use regex::Regex;
-use std::collections::{HashMap, HashSet};
+use log4rs::append::rolling_file::policy::compound::{
+    roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger, CompoundPolicy,
+};
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::config::{Appender, Logger, Root};
+use log4rs::encode::{self, pattern::PatternEncoder, Encode};
+use log4rs::Config as LogConfig;
+use lru::LruCache;
+use mysql::{params, prelude::*, Pool, PooledConn, Transaction, TxOpts, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::io::Write;
-use std::ops::{AddAssign, SubAssign};
-use std::sync::{Arc, Mutex};
-use tokio::runtime::Builder as RuntimeBuilder;
-use tokio::task::JoinHandle;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, IsTerminal, Write};
+use std::num::NonZeroUsize;
+use std::ops::AddAssign;
+use std::sync::{Arc, Condvar, Mutex, Once, OnceLock};
+use tokio::runtime::{Builder as RuntimeBuilder, Runtime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Semaphore;
+use tokio::task::{JoinError, JoinHandle};
 use tokio::time::{self, Duration, Instant};
 use urlencoding::decode;
 
-const WIKIPEDIA_NAMESPACES: [&str; 28] = [
-    "média:",
-    "spécial:",
-    "discussion:",
-    "utilisateur:",
-    "discussion_utilisateur:",
-    "wikipédia:",
-    "discussion_wikipédia:",
-    "fichier:",
-    "discussion_fichier:",
-    "mediawiki:",
-    "discussion_mediawiki:",
-    "modèle:",
-    "discussion_modèle:",
-    "aide:",
-    "discussion_aide:",
-    "catégorie:",
-    "discussion_catégorie:",
-    "portail:",
-    "discussion_portail:",
-    "projet:",
-    "discussion_projet:",
-    "référence:",
-    "discussion_référence:",
-    "timedtext:",
-    "timedtext_talk:",
-    "module:",
-    "discussion_module:",
-    "sujet:",
-];
 const MAX_SAME_ERROR: usize = 3;
+const REQUEUE_CHUNK: usize = 5000;
+// a prepared statement has at most 65535 placeholders
+const MAX_PLACEHOLDERS: usize = 65535;
+const PAUSE_POLL: Duration = Duration::from_millis(200);
+// number of batches of the rolling average throughput
+const RATE_WINDOW: usize = 10;
+// percentage of the pages of a batch going through retries above which the next batch is smaller
+const ADAPT_ERROR_RATE: usize = 10;
+// the first of them ends the article text kept by WIKICRAWL_ARTICLE_LINKS_ONLY
+const ARTICLE_END_MARKERS: [&str; 2] = ["class=\"navbox", "<footer"];
 
-struct TotalInfo {
-    explored: usize,
-    bugged: usize,
-    pages: usize,
-    links: usize,
+static SIGNALS: OnceLock<Arc<Signals>> = OnceLock::new();
+static SIGNAL_HANDLERS: Once = Once::new();
+
+// the counters of the run that TotalInfo doesn't have, written to the metrics file after each batch
+#[derive(Debug, Default)]
+struct Metrics {
+    link_lookups: usize,
+    cache_hits: usize,
+    pages_per_second: f64,
+    links_per_second: f64,
+}
+
+// the error of a bugged page is recorded in BuggedPages
+type ExploreResult = (Page, Result<ExploredPage, String>);
+
+// the article links of a page as (link, display), and its categories without their namespace
+pub(crate) struct ExploredPage {
+    pub(crate) links: Vec<(String, String)>,
+    pub(crate) categories: Vec<String>,
+}
+
+pub struct TotalInfo {
+    pub run_id: String,
+    pub started_at: Instant,
+    pub run_explored: usize,
+    pub explored: usize,
+    pub bugged: usize,
+    pub pages: usize,
+    pub links: usize,
 }
 
+// weight is the number of distinct anchors from linker to linked, whichever display is kept
 struct Link<'a> {
     linker: usize,
     linked: usize,
     display: &'a String,
+    weight: usize,
 }
 
 impl<'a> PartialEq for Link<'a> {
@@ -78,115 +96,362 @@ impl<'a> std::hash::Hash for Link<'a> {
     }
 }
 
+#[derive(Debug)]
+pub enum WikicrawlError {
+    Mysql(mysql::Error),
+    Http(reqwest::Error),
+    Io(std::io::Error),
+    NoUnexploredPages,
+    Join(JoinError),
+    Extract(ExtractError),
+}
+
+impl Display for WikicrawlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WikicrawlError::Mysql(error) => write!(f, "{}", error),
+            WikicrawlError::Http(error) => write!(f, "{}", error),
+            WikicrawlError::Io(error) => write!(f, "{}", error),
+            WikicrawlError::NoUnexploredPages => write!(f, "No unexplored pages found"),
+            WikicrawlError::Join(error) => write!(f, "{}", error),
+            WikicrawlError::Extract(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for WikicrawlError {}
+
+impl From<mysql::Error> for WikicrawlError {
+    fn from(error: mysql::Error) -> Self {
+        WikicrawlError::Mysql(error)
+    }
+}
+
+impl From<reqwest::Error> for WikicrawlError {
+    fn from(error: reqwest::Error) -> Self {
+        WikicrawlError::Http(error)
+    }
+}
+
+impl From<std::io::Error> for WikicrawlError {
+    fn from(error: std::io::Error) -> Self {
+        WikicrawlError::Io(error)
+    }
+}
+
+impl From<ExtractError> for WikicrawlError {
+    fn from(error: ExtractError) -> Self {
+        WikicrawlError::Extract(error)
+    }
+}
+
+impl From<JoinError> for WikicrawlError {
+    fn from(error: JoinError) -> Self {
+        WikicrawlError::Join(error)
+    }
+}
+
+// cancel stops the crawl once the pages being explored are saved, the pages of the batch not started yet
+// staying unexplored, paused holds the workers before their next request, forced also skips the lookups
+// of the new links so that only what was already fetched is saved
+struct Signals {
+    cancel: Mutex<bool>,
+    forced: Mutex<bool>,
+    paused: Mutex<bool>,
+    resumed: Condvar,
+}
+
+impl Signals {
+    fn new() -> Signals {
+        Signals {
+            cancel: Mutex::new(false),
+            forced: Mutex::new(false),
+            paused: Mutex::new(false),
+            resumed: Condvar::new(),
+        }
+    }
+
+    // the flags of a previous crawl of the process are still set
+    fn reset(&self) {
+        *self.cancel.lock().unwrap() = false;
+        *self.forced.lock().unwrap() = false;
+        *self.paused.lock().unwrap() = false;
+        self.resumed.notify_all();
+    }
+
+    // the workers run on multi thread runtimes, so they can block in place while the batch is paused
+    fn wait_while_paused(&self) {
+        tokio::task::block_in_place(|| {
+            let _paused = self
+                .resumed
+                .wait_while(self.paused.lock().unwrap(), |paused| *paused)
+                .unwrap();
+        });
+    }
+
+    // the lookups of the new pages are many more tasks than threads, so they sleep instead of blocking one
+    async fn paused(&self) {
+        while *self.paused.lock().unwrap() {
+            time::sleep(PAUSE_POLL).await;
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        *self.cancel.lock().unwrap()
+    }
+
+    fn is_forced(&self) -> bool {
+        *self.forced.lock().unwrap()
+    }
+}
+
+// what the batches of a crawl share across its restarts: the flags set by the signals, and the runtime
+// exploring the pages and looking their new links up, built once instead of for every batch
+struct CrawlContext {
+    signals: Arc<Signals>,
+    runtime: Runtime,
+}
+
+impl CrawlContext {
+    fn new(signals: Arc<Signals>, crawl_config: &CrawlConfig) -> Result<Self, std::io::Error> {
+        let runtime = RuntimeBuilder::new_multi_thread()
+            .worker_threads(crawl_config.workers)
+            .enable_all()
+            .thread_name("wikicrawl exploring".to_string())
+            .build()?;
+        Ok(CrawlContext { signals, runtime })
+    }
+
+    // a runtime can't be dropped from an async context, the tasks left by a failed batch are dropped with it
+    fn shutdown(self) {
+        self.runtime.shutdown_background();
+    }
+}
+
+// opens once more than max_rate percent of the pages of a batch went through the retries of explore,
+// the rest of the batch is then skipped like after a forced stop, 0 never opens
+pub(crate) struct CircuitBreaker {
+    pages: usize,
+    max_rate: usize,
+    failing: Mutex<HashSet<usize>>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(pages: usize, max_rate: usize) -> CircuitBreaker {
+        CircuitBreaker {
+            pages,
+            max_rate,
+            failing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn record_failure(&self, page: &Page) {
+        self.failing.lock().unwrap().insert(page.id);
+    }
+
+    fn failing(&self) -> usize {
+        self.failing.lock().unwrap().len()
+    }
+
+    fn is_open(&self) -> bool {
+        self.max_rate > 0 && self.failing() * 100 > self.max_rate * self.pages
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CrawlConfig {
+    // the size of the first batch, the next ones adapt to the last batch within the bounds,
+    // equal bounds keeping every batch at the first size
+    pub max_exploring_pages: usize,
+    pub min_batch_pages: usize,
+    pub max_batch_pages: usize,
+    // the batches taking less than half of it grow, those taking longer shrink
+    pub target_batch_time: Duration,
+    pub max_new_pages: usize,
+    pub insert_chunk_size: usize,
+    pub link_cache_size: usize,
+    // the batch sizes only set how many tasks are queued, the runtime of the crawl never uses more threads than this
+    pub workers: usize,
+    // connections of wikipath querying the links of a depth at once, lower than the size of the pool
+    pub search_workers: usize,
+    // pages whose links are read by each query of wikipath, bounded by the max_allowed_packet of mysql
+    pub path_chunk: usize,
+    pub seed_title: String,
+    pub log_format: LogFormat,
+    pub log_max_mb: usize,
+    pub log_archives: usize,
+    // headless runs only log to the files, without the console logs and progress bars
+    pub quiet: bool,
+    // the queries of every command are logged with their duration, to stderr outside of the crawl logs
+    pub verbose_sql: bool,
+    // 0 disables the limit, both are counted from the start of this run
+    pub max_pages: usize,
+    pub max_duration: Duration,
+    // only the pages with start_id <= id <= max_id are explored, 0 leaving the bound open,
+    // so that several machines can crawl disjoint ranges
+    pub start_id: usize,
+    pub max_id: usize,
+    // explored pages older than this many days are explored again and their links upserted, 0 never does
+    pub recrawl_days: usize,
+    // multiple of the latency of the first batch above which a slowdown is logged, 0 disables it
+    pub latency_alert: f64,
+    // prometheus textfile updated after each batch
+    pub metrics_file: Option<String>,
+    // the extracted links are also written to this file, one json object per line
+    pub jsonl_out: Option<String>,
+    // the links and categories are only written to jsonl_out, the database only keeping the pages
+    // to explore, so that wikicrawl is a pure extractor
+    pub jsonl_only: bool,
+    // bugged pages that failed this many times aren't requeued anymore, 0 always requeues them
+    pub max_failures: usize,
+    // percentage of the pages of a batch going through retries that skips the rest of the batch,
+    // followed by a cooldown before the next one, 0 disables it
+    pub max_failure_rate: usize,
+    pub failure_cooldown: Duration,
+    // minutes after which the pages claimed by a run are considered abandoned and claimed again
+    pub claim_timeout: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Option<LogFormat> {
+        match format.trim().to_ascii_lowercase().as_str() {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+// one json object per line for the log files, so they can be ingested as is
+#[derive(Debug)]
+struct JsonLineEncoder {
+    run_id: String,
+}
+
+impl Encode for JsonLineEncoder {
+    fn encode(&self, w: &mut dyn encode::Write, record: &Record) -> anyhow::Result<()> {
+        let line = serde_json::json!({
+            "timestamp": Local::now().to_rfc3339(),
+            "level": record.level().to_string(),
+            "message": record.args().to_string(),
+            "run_id": self.run_id,
+        });
+        writeln!(w, "{}", line)?;
+        Ok(())
+    }
+}
+
 pub async fn setup_wikicrawl(
+    pool: &Pool,
     connection: &mut PooledConn,
-    max_exploring_pages: usize,
-    max_new_pages: usize,
-) -> () {
-    println!("setting up logs");
-    setup_logs().unwrap();
+    crawl_config: &CrawlConfig,
+    wiki_config: &WikiConfig,
+) -> Result<(), WikicrawlError> {
+    let run_id = new_run_id();
+    if !crawl_config.quiet {
+        println!("setting up logs");
+    }
+    setup_logs(crawl_config, &run_id)?;
     info!("Starting wikicrawl");
 
-    info!("creating SIGINT thread");
-    let sigint_cancel = Arc::new(Mutex::new(false));
-    let sigint_cancel_clone = Arc::clone(&sigint_cancel);
-    ctrlc::set_handler(move || {
-        let mut cancel = sigint_cancel_clone.lock().unwrap();
-        match *cancel {
-            false => {
-                info!("SIGINT received, waiting for the program to stop");
-                *cancel = true;
-            }
-            true => {
-                info!("SIGINT received, forcing the program to stop");
-                std::process::exit(0);
-            }
-        }
-    })
-    .unwrap();
+    let signals = Arc::clone(SIGNALS.get_or_init(|| Arc::new(Signals::new())));
+    signals.reset();
+    SIGNAL_HANDLERS.call_once(|| install_signal_handlers(&signals));
 
-    let mut total_info = TotalInfo {
-        explored: 0,
-        bugged: 0,
-        pages: 0,
-        links: 0,
-    };
-    info!("querying total explored pages");
-    total_info.explored = connection
-        .query_first("SELECT COUNT(*) FROM Pages WHERE explored = TRUE;")
-        .unwrap_or(Some(0))
-        .unwrap_or(0);
-    info!("querying total bugged pages");
-    total_info.bugged = connection
-        .query_first("SELECT COUNT(*) FROM Pages WHERE bugged = TRUE;")
-        .unwrap_or(Some(0))
-        .unwrap_or(0);
-    info!("querying total pages");
-    total_info.pages = connection
-        .query_first("SELECT COUNT(*) FROM Pages;")
-        .unwrap_or(Some(0))
-        .unwrap_or(0);
-    info!("querying total links");
-    total_info.links = connection
-        .query_first("SELECT COUNT(*) FROM Links;")
-        .unwrap_or(Some(0))
-        .unwrap_or(0);
+    info!("creating the tables if they don't exist");
+    if let Err(error) = ensure_schema(connection) {
+        error!("couldn't create the database schema: {}", error);
+        return Err(error.into());
+    }
 
-    let error_regex = Regex::new(r"(?m)ERROR ([0-9]+) ").unwrap();
-    let mut error_count: HashMap<usize, usize> = HashMap::new();
-    loop {
+    let mut total_info = query_total_info(connection);
+    total_info.run_id = run_id;
+    info!("starting run {}", total_info.run_id);
+
+    let context = CrawlContext::new(Arc::clone(&signals), crawl_config)?;
+    let mut error_count: HashMap<u16, usize> = HashMap::new();
+    // the error that stopped the restarts
+    let result = loop {
+        // a forced stop during the restart delay doesn't start another run
+        if signals.is_forced() {
+            break Ok(());
+        }
         let mut last_query: String = String::new();
         let mut exploring_pages: Vec<Page> = Vec::new();
-        *sigint_cancel.lock().unwrap() = false;
+        *signals.cancel.lock().unwrap() = false;
         let result = wikicrawl(
             &mut last_query,
             &mut exploring_pages,
             &mut total_info,
             connection,
-            &sigint_cancel,
-            max_exploring_pages,
-            max_new_pages,
+            &context,
+            crawl_config,
+            wiki_config,
         )
         .await;
-        *sigint_cancel.lock().unwrap() = true;
+        *signals.cancel.lock().unwrap() = true;
 
-        if result.is_err() {
+        if let Err(error) = result {
             if !last_query.is_empty() {
                 error!("WIKICRAWL CRASHED WITH LAST QUERY BEING");
                 error!("{}", last_query);
             } else {
                 error!("WIKICRAWL CRASHED");
             }
-            let error = result.unwrap_err().to_string();
             error!("{}", error);
 
-            last_query = format!(
-                "UPDATE Pages SET bugged = TRUE WHERE id IN ({});",
-                exploring_pages
-                    .into_iter()
-                    .map(|page| page.id.to_string())
-                    .collect::<Vec<String>>()
-                    .join(",")
-            );
-            error!("");
-            error!("Marking all unexplored pages as bugged");
-            error!("executing query {}", last_query);
-            connection.query_drop(last_query).unwrap_or_else(|e| {
-                error!("couldn't mark all unexplored pages as bugged");
-                error!("{}", e);
-            });
+            // the pages of the batch are still unexplored, the next batch picks them up again
+            if let WikicrawlError::Mysql(mysql_error) = &error {
+                if is_connection_lost(mysql_error) {
+                    let count = error_count
+                        .entry(2013)
+                        .and_modify(|counter| *counter += 1)
+                        .or_insert(1);
+                    if *count > MAX_SAME_ERROR {
+                        error!("Too many lost connections, stopping the program");
+                        break Err(error);
+                    }
+                    info!("connection to the database lost, getting a new one from the pool");
+                    match pool.get_conn() {
+                        Ok(new_connection) => {
+                            *connection = new_connection;
+                            continue;
+                        }
+                        Err(error) => {
+                            error!("couldn't get a new connection: {}", error);
+                            break Err(error.into());
+                        }
+                    }
+                }
+            }
 
-            let error_captures = error_regex.captures(&error);
-            match error_captures {
-                Some(capture) => {
-                    let error_code = capture.get(1).unwrap().as_str().parse::<usize>().unwrap();
+            // a failed batch is rolled back and its pages are still unexplored, they are released to be
+            // explored again instead of blamed for it. the pages whose own exploration failed are
+            // already marked as bugged, outside of the transaction
+            if !exploring_pages.is_empty() {
+                info!("releasing the {} uncommitted pages", exploring_pages.len());
+                if let Err(release_error) =
+                    release_claims(&mut last_query, connection, &total_info.run_id)
+                {
+                    error!("couldn't release the uncommitted pages: {}", release_error);
+                }
+            }
+
+            match &error {
+                WikicrawlError::Mysql(mysql::Error::MySqlError(mysql_error)) => {
                     let count = error_count
-                        .entry(error_code)
+                        .entry(mysql_error.code)
                         .and_modify(|counter| *counter += 1)
                         .or_insert(1);
                     if *count > MAX_SAME_ERROR {
                         error!("Too many same errors, stopping the program");
-                        break;
+                        break Err(error);
                     } else {
                         info!("program restarting in 10 seconds ...");
                         info!("Press CTRL + C to stop.");
@@ -194,16 +459,289 @@ pub async fn setup_wikicrawl(
                         continue;
                     }
                 }
-                None => {
-                    error!("Couldn't find error code in error message");
-                    error!("Stopping the program");
+                _ => {
+                    error!("Not a MySQL server error, stopping the program");
+                    break Err(error);
                 }
             }
         }
 
         info!("WIKICRAWL FINISHED");
-        return ();
+        break Ok(());
+    };
+    context.shutdown();
+    // running out of pages to explore is the end of the crawl, not a failure
+    match result {
+        Err(WikicrawlError::NoUnexploredPages) => Ok(()),
+        result => result,
+    }
+}
+
+// SIGINT: the first lets the pages being explored finish and be saved with their links then stops,
+// the second saves what the batch fetched without looking the new links up, the third exits.
+// SIGTSTP pauses and resumes the requests. ctrlc panics when set twice, so they are installed once
+// per process and share SIGNALS between the crawls
+fn install_signal_handlers(signals: &Arc<Signals>) {
+    info!("creating SIGINT thread");
+    let sigint_signals = Arc::clone(signals);
+    ctrlc::set_handler(move || {
+        let mut cancel = sigint_signals.cancel.lock().unwrap();
+        let mut forced = sigint_signals.forced.lock().unwrap();
+        match (*cancel, *forced) {
+            (false, _) => {
+                info!("SIGINT received, finishing the pages being explored before stopping");
+                *cancel = true;
+            }
+            (true, false) => {
+                info!("SIGINT received, saving what the current batch already fetched");
+                *forced = true;
+                // paused workers would never see the flag
+                *sigint_signals.paused.lock().unwrap() = false;
+                sigint_signals.resumed.notify_all();
+            }
+            (true, true) => {
+                info!("SIGINT received, forcing the program to stop");
+                std::process::exit(0);
+            }
+        }
+    })
+    .unwrap();
+
+    info!("creating SIGTSTP thread");
+    let sigtstp_signals = Arc::clone(signals);
+    let mut sigtstp = signal(SignalKind::from_raw(libc::SIGTSTP)).unwrap();
+    tokio::spawn(async move {
+        while sigtstp.recv().await.is_some() {
+            let mut paused = sigtstp_signals.paused.lock().unwrap();
+            *paused = !*paused;
+            if *paused {
+                info!("SIGTSTP received, pausing the requests");
+            } else {
+                info!("SIGTSTP received, resuming the requests");
+                sigtstp_signals.resumed.notify_all();
+            }
+        }
+    });
+}
+
+fn new_run_id() -> String {
+    format!(
+        "{}_{}",
+        Local::now().format("%Y-%m-%d_%H:%M:%S"),
+        std::process::id()
+    )
+}
+
+// crawls until a limit of the config is reached or no unexplored page is left, without the logs,
+// signal handlers and restarts of setup_wikicrawl, which are left to the calling program
+pub async fn crawl(
+    connection: &mut PooledConn,
+    config: &Config,
+) -> Result<TotalInfo, WikicrawlError> {
+    ensure_schema(connection)?;
+    let mut total_info = query_total_info(connection);
+    total_info.run_id = new_run_id();
+
+    let mut last_query = String::new();
+    let mut exploring_pages: Vec<Page> = Vec::new();
+    let context = CrawlContext::new(Arc::new(Signals::new()), &config.crawl)?;
+    let result = wikicrawl(
+        &mut last_query,
+        &mut exploring_pages,
+        &mut total_info,
+        connection,
+        &context,
+        &config.crawl,
+        &config.wiki,
+    )
+    .await;
+    context.shutdown();
+    match result {
+        Ok(()) | Err(WikicrawlError::NoUnexploredPages) => Ok(total_info),
+        Err(error) => Err(error),
+    }
+}
+
+// 2006 is "server has gone away" and 2013 "lost connection during query", the driver mostly reports them as io errors
+pub(crate) fn is_connection_lost(error: &mysql::Error) -> bool {
+    match error {
+        mysql::Error::IoError(_) => true,
+        mysql::Error::DriverError(mysql::DriverError::CouldNotConnect(_)) => true,
+        mysql::Error::MySqlError(mysql_error) => {
+            mysql_error.code == 2006 || mysql_error.code == 2013
+        }
+        _ => false,
+    }
+}
+
+fn query_total_info(connection: &mut PooledConn) -> TotalInfo {
+    let mut total_info = TotalInfo {
+        run_id: String::new(),
+        started_at: Instant::now(),
+        run_explored: 0,
+        explored: 0,
+        bugged: 0,
+        pages: 0,
+        links: 0,
+    };
+    let mut count = |query: &str| -> usize {
+        timed(query, || connection.query_first(query))
+            .unwrap_or(Some(0))
+            .unwrap_or(0)
+    };
+    info!("querying total explored pages");
+    total_info.explored = count("SELECT COUNT(*) FROM Pages WHERE explored = TRUE;");
+    info!("querying total bugged pages");
+    total_info.bugged = count("SELECT COUNT(*) FROM Pages WHERE bugged = TRUE;");
+    info!("querying total pages");
+    total_info.pages = count("SELECT COUNT(*) FROM Pages;");
+    info!("querying total links");
+    total_info.links = count("SELECT COUNT(*) FROM Links;");
+    total_info
+}
+
+// a read only report of the graph, it doesn't touch the crawl state nor the SIGINT handler
+pub fn setup_stats(connection: &mut PooledConn) {
+    if let Err(error) = ensure_schema(connection) {
+        println!("Couldn't create the database schema: {}", error);
+        return;
+    }
+
+    let total_info = query_total_info(connection);
+    let query = "SELECT Pages.id, Pages.title, COUNT(*) AS count FROM Links JOIN Pages ON Pages.id = Links.linked GROUP BY Pages.id, Pages.title ORDER BY count DESC LIMIT 20;";
+    let top_pages = match timed(query, || {
+        connection.query_map(query, |(id, title, count): (usize, String, usize)| {
+            (Page { id, title }, count)
+        })
+    }) {
+        Ok(top_pages) => top_pages,
+        Err(error) => {
+            println!("Error: {}", error);
+            return;
+        }
+    };
+
+    println!();
+    println!("{:<20} {:>12}", "pages", total_info.pages);
+    println!("{:<20} {:>12}", "explored pages", total_info.explored);
+    println!("{:<20} {:>12}", "bugged pages", total_info.bugged);
+    println!("{:<20} {:>12}", "links", total_info.links);
+    println!(
+        "{:<20} {:>12.2}",
+        "average out-degree",
+        if total_info.explored == 0 {
+            0.0
+        } else {
+            total_info.links as f64 / total_info.explored as f64
+        }
+    );
+
+    println!();
+    println!("most linked pages:");
+    println!("{:>4} {:>10} {:>10}  title", "rank", "links", "id");
+    for (rank, (page, count)) in top_pages.iter().enumerate() {
+        println!(
+            "{:>4} {:>10} {:>10}  {}",
+            rank + 1,
+            count,
+            page.id,
+            page.title
+        );
+    }
+}
+
+// dead ends are explored pages without links, orphans are pages no other page links to,
+// both are often pages where explore couldn't extract the links
+pub fn setup_diagnostics(connection: &mut PooledConn) {
+    if let Err(error) = ensure_schema(connection) {
+        println!("Couldn't create the database schema: {}", error);
+        return;
+    }
+
+    let reports = [
+        (
+            "dead end pages",
+            "FROM Pages LEFT JOIN Links ON Pages.id = Links.linker WHERE Links.linker IS NULL AND Pages.explored = TRUE",
+        ),
+        (
+            "orphan pages",
+            "FROM Pages LEFT JOIN Links ON Pages.id = Links.linked WHERE Links.linked IS NULL",
+        ),
+    ];
+    for (name, from) in reports {
+        println!();
+        let query = format!("SELECT COUNT(*) {};", from);
+        let count = timed(&query, || connection.query_first(&query));
+        let query = format!("SELECT Pages.id, Pages.title {} LIMIT 10;", from);
+        let sample = timed(&query, || {
+            connection.query_map(&query, |(id, title): (usize, String)| Page { id, title })
+        });
+        match (count, sample) {
+            (Ok(count), Ok(sample)) => {
+                println!("{}: {}", name, count.unwrap_or(0_usize));
+                for page in sample {
+                    println!("    Page {}", page);
+                }
+            }
+            (Err(error), _) | (_, Err(error)) => {
+                println!("Error while looking for {}: {}", name, error);
+                return;
+            }
+        }
+    }
+}
+
+// bugged pages are never explored again, this puts them back in the crawl queue
+// except the pages that failed max_failures times, 0 requeuing them all
+pub fn setup_requeue(connection: &mut PooledConn, before: Option<&String>, max_failures: usize) {
+    if let Err(error) = ensure_schema(connection) {
+        println!("Couldn't create the database schema: {}", error);
+        return;
+    }
+
+    let before = match before {
+        Some(before) => before.clone(),
+        None => ask(
+            "\nRequeue the pages bugged before which date ? (YYYY-MM-DD, or nothing for all) \n-> ",
+        ),
+    };
+    // pages bugged before bugged_at existed have no date and are considered older than any date
+    let mut condition = match before.trim() {
+        "" => String::new(),
+        before => match NaiveDate::parse_from_str(before, "%Y-%m-%d") {
+            Ok(date) => format!(" AND (bugged_at IS NULL OR bugged_at < '{}')", date),
+            Err(_) => {
+                println!("Invalid date \"{}\"", before);
+                return;
+            }
+        },
+    };
+    if max_failures > 0 {
+        condition.push_str(&format!(
+            " AND id NOT IN (SELECT id FROM BuggedPages WHERE failures >= {})",
+            max_failures
+        ));
+    }
+
+    let mut requeued = 0;
+    loop {
+        let query = format!(
+            "UPDATE Pages SET bugged = FALSE, bugged_at = NULL, explored = FALSE WHERE bugged = TRUE{} LIMIT {};",
+            condition, REQUEUE_CHUNK
+        );
+        let result = timed(&query, || connection.query_drop(&query));
+        if let Err(error) = result {
+            println!("Error: {}", error);
+            break;
+        }
+        let affected_rows = connection.affected_rows() as usize;
+        requeued += affected_rows;
+        if affected_rows < REQUEUE_CHUNK {
+            break;
+        }
+        println!("requeued {} pages", requeued);
     }
+    println!("requeued {} bugged pages", requeued);
 }
 
 async fn wikicrawl(
@@ -211,10 +749,30 @@ async fn wikicrawl(
     exploring_pages: &mut Vec<Page>,
     total_info: &mut TotalInfo,
     connection: &mut PooledConn,
-    sigint_cancel: &Arc<Mutex<bool>>,
-    max_exploring_pages: usize,
-    max_new_pages: usize,
-) -> Result<(), Box<dyn Error>> {
+    context: &CrawlContext,
+    crawl_config: &CrawlConfig,
+    wiki_config: &WikiConfig,
+) -> Result<(), WikicrawlError> {
+    let signals = &context.signals;
+    let mut batch_size = crawl_config.max_exploring_pages;
+    let max_new_pages = crawl_config.max_new_pages;
+    let shared_config = Arc::new(wiki_config.clone());
+    // resolved links are kept between batches, popular links are found again and again
+    let shared_cache: Arc<Mutex<LruCache<String, Page>>> = Arc::new(Mutex::new(LruCache::new(
+        NonZeroUsize::new(crawl_config.link_cache_size).unwrap(),
+    )));
+    // (explored pages, inserted links, wall-clock time) of the last batches
+    let mut batch_rates: VecDeque<(usize, usize, Duration)> = VecDeque::new();
+    let mut metrics = Metrics::default();
+    // the latency of the requests once the first batch is over, later ones are compared to it
+    let mut baseline_latency: Option<Duration> = None;
+    // the file is appended to, so that the restarts of a run keep the links already written
+    let mut jsonl_out = match &crawl_config.jsonl_out {
+        Some(path) => Some(BufWriter::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        None => None,
+    };
     info!("");
     info!(
         "explored {} pages (with {} bugged)",
@@ -224,27 +782,57 @@ async fn wikicrawl(
     info!("listed {} links", total_info.links);
     info!("");
 
-    while {
-        let temp = sigint_cancel.lock().unwrap();
-        !*temp
-    } {
-        // get unexplored pages
+    while !signals.is_cancelled() {
+        let batch_now = Instant::now();
+        let mut batch_links = 0;
+
+        // the pages left to explore, this batch included, for the estimate of the remaining time
+        release_claims(last_query, connection, &total_info.run_id)?;
+        last_query.clear();
+        last_query.push_str(&format!(
+            "SELECT COUNT(*) FROM Pages WHERE {} AND bugged = false{};",
+            to_explore_condition(crawl_config),
+            id_range_condition(crawl_config)
+        ));
+        let remaining_pages: usize =
+            timed(last_query, || connection.query_first(&last_query))?.unwrap_or(0);
+
+        // claim unexplored pages, so that the other machines crawling this database skip them
         last_query.clear();
         last_query.push_str(&format!(
-            "SELECT id, title FROM Pages WHERE explored = false AND bugged = false ORDER BY id ASC LIMIT {};",
-            max_exploring_pages
+            "UPDATE Pages SET claimed_by = ?, claimed_at = NOW() WHERE {} AND bugged = false{} AND (claimed_by IS NULL OR claimed_at < NOW() - INTERVAL {} MINUTE) ORDER BY id ASC LIMIT {};",
+            to_explore_condition(crawl_config),
+            id_range_condition(crawl_config),
+            crawl_config.claim_timeout,
+            batch_size
         ));
-        info!("getting unexplored pages");
+        info!("claiming unexplored pages");
+        timed(last_query, || {
+            connection.exec_drop(&last_query, (&total_info.run_id,))
+        })?;
+        last_query.clear();
+        last_query.push_str("SELECT id, title FROM Pages WHERE claimed_by = ? ORDER BY id ASC;");
+        info!("getting the claimed pages");
         exploring_pages.clear();
-        exploring_pages.extend(
-            connection
-                .query_map(&last_query, |(id, title)| Page { id, title })?
-                .into_iter(),
-        );
+        exploring_pages.extend(timed(last_query, || {
+            connection.exec_map(&last_query, (&total_info.run_id,), |(id, title)| Page {
+                id,
+                title,
+            })
+        })?);
         let unexplored_length = exploring_pages.len();
         if unexplored_length < 1 {
+            last_query.clear();
+            last_query.push_str("SELECT COUNT(*) FROM Pages;");
+            let pages_count: usize =
+                timed(last_query, || connection.query_first(&last_query))?.unwrap_or(0);
+            if pages_count == 0 {
+                seed(last_query, connection, crawl_config, wiki_config).await?;
+                total_info.pages += 1;
+                continue;
+            }
             error!("No unexplored pages found");
-            return Err(Box::from("No unexplored pages found"));
+            return Err(WikicrawlError::NoUnexploredPages);
         }
         info!(
             "Exploring pages: [{}]",
@@ -255,120 +843,124 @@ async fn wikicrawl(
                 .join(", ")
         );
 
-        // delete potential links from an old run
-        info!("deleting potential links from an old run");
-        last_query.clear();
-        last_query.push_str(&format!(
-            "DELETE FROM Links WHERE linker IN ({});",
-            exploring_pages
-                .iter()
-                .map(|page| page.id.to_string())
-                .collect::<Vec<String>>()
-                .join(", "),
-        ));
-        connection.query_drop(&last_query)?;
-
-        let mut results: Vec<(Page, Vec<(String, String)>)> = Vec::new();
-        let mut bugged_pages: Vec<Page> = Vec::new();
-        let mut children: Vec<JoinHandle<(Page, Option<Vec<(String, String)>>)>> = Vec::new();
+        let mut results: Vec<(Page, ExploredPage)> = Vec::new();
+        let mut bugged_pages: Vec<(Page, String)> = Vec::new();
+        let mut children: Vec<JoinHandle<Option<ExploreResult>>> = Vec::new();
         let now = Instant::now();
-        let shared_explored_count = Arc::new(Mutex::new(0 as usize));
+        let shared_explored_count = Arc::new(Mutex::new(0_usize));
 
         info!("exploring pages");
+        let exploring_bar = progress_bar(unexplored_length, "exploring", crawl_config.quiet);
 
-        let exploring_runtime = RuntimeBuilder::new_multi_thread()
-            .worker_threads(unexplored_length)
-            .enable_all()
-            .thread_name("wikicrawl exploring".to_string())
-            .build()?;
+        let breaker = Arc::new(CircuitBreaker::new(
+            unexplored_length,
+            crawl_config.max_failure_rate,
+        ));
         exploring_pages.clone().into_iter().for_each(|page| {
             let thread_explored_count = Arc::clone(&shared_explored_count);
-            let child = exploring_runtime.spawn(async move {
-                let explore_result = explore(&page).await;
+            let thread_bar = exploring_bar.clone();
+            let thread_config = Arc::clone(&shared_config);
+            let thread_signals = Arc::clone(signals);
+            let thread_breaker = Arc::clone(&breaker);
+            let child = context.runtime.spawn(async move {
+                thread_signals.wait_while_paused();
+                // a skipped page stays unexplored for the next run, the pages already being explored
+                // when the crawl is cancelled are still saved
+                if thread_signals.is_cancelled() || thread_breaker.is_open() {
+                    return None;
+                }
+                let explore_result =
+                    explore(&page, &thread_config, &*CLIENT, &thread_breaker).await;
                 let count = {
                     let mut tmp = thread_explored_count.lock().unwrap();
                     (*tmp).add_assign(1);
                     *tmp
                 };
-                print!(
-                    "explored {}/{} pages ({}%)      \r",
-                    count,
-                    unexplored_length,
-                    100 * count / unexplored_length
-                );
-                std::io::stdout().flush().unwrap();
+                thread_bar.set_position(count as u64);
                 match explore_result {
-                    Ok(links) => {
-                        if links.is_empty() {
-                            (page, None)
+                    Ok(explored_page) => {
+                        if explored_page.links.is_empty() {
+                            Some((page, Err("no links found".to_string())))
                         } else {
-                            (page, Some(links))
+                            Some((page, Ok(explored_page)))
                         }
                     }
-                    Err(_) => (page, None),
+                    // the page isn't to blame when most of the batch fails
+                    Err(_) if thread_breaker.is_open() => None,
+                    Err(error) => Some((page, Err(error.to_string()))),
                 }
             });
             children.push(child);
         });
 
-        print!("explored 0/{} pages (0%)      \r", unexplored_length);
-        std::io::stdout().flush()?;
-        for child in children.into_iter() {
-            let (page, links) = child.await?;
-            match links {
-                Some(links) => results.push((page, links)),
-                None => bugged_pages.push(page),
+        let mut skipped_pages: HashSet<usize> = HashSet::new();
+        for (page, child) in exploring_pages.iter().zip(children) {
+            match child.await? {
+                Some((page, Ok(explored_page))) => results.push((page, explored_page)),
+                Some((page, Err(error))) => bugged_pages.push((page, error)),
+                None => {
+                    skipped_pages.insert(page.id);
+                }
             }
         }
-        exploring_runtime.shutdown_background();
+        exploring_pages.retain(|page| !skipped_pages.contains(&page.id));
+        exploring_bar.finish_and_clear();
 
         info!(
             "explored {} pages in {} ms",
-            unexplored_length,
+            unexplored_length - skipped_pages.len(),
             now.elapsed().as_millis()
         );
 
         // mark as bugged if there are
         if !bugged_pages.is_empty() {
-            last_query.clear();
-            last_query.push_str(&format!(
-                "UPDATE Pages SET bugged = TRUE WHERE id IN ({});",
-                bugged_pages
-                    .iter()
-                    .map(|page| page.id.to_string())
-                    .collect::<Vec<String>>()
-                    .join(","),
-            ));
             info!("marking bugged pages");
-            connection.query_drop(&last_query)?;
+            save_bugged_pages(
+                last_query,
+                connection,
+                &bugged_pages,
+                crawl_config.insert_chunk_size,
+            )?;
             info!("marked {} bugged pages", bugged_pages.len());
             total_info.bugged += bugged_pages.len();
         }
 
         let found_links = results
             .iter()
-            .map(|(_, links)| links.clone().into_iter().map(|(link, _display)| link))
-            .flatten()
+            .flat_map(|(_, explored_page)| {
+                explored_page
+                    .links
+                    .iter()
+                    .map(|(link, _display)| link.clone())
+            })
             .collect::<HashSet<String>>();
         info!("found {} links", found_links.len());
 
-        if found_links.len() > 0 {
+        // the links of the pages are resolved first, the requests staying out of the transaction of the batch
+        let lookups = if found_links.is_empty() {
+            None
+        } else {
             let now = Instant::now();
-            last_query.clear();
-            last_query.push_str(&format!(
-			"SELECT Alias.alias, Pages.id, Pages.title FROM Pages JOIN Alias ON Pages.id = Alias.id WHERE alias IN ({});", 
-			found_links
-				.iter()
-				.map(|link| format!("\"{}\"", format_link_for_mysql(link)))
-				.collect::<Vec<String>>()
-				.join(", ")));
-            let mut old_pages = connection
-                .query_map(
-                    &last_query,
-                    |(alias, id, title): (String, usize, String)| (alias, Page { id, title }),
-                )?
-                .into_iter()
-                .collect::<HashMap<String, Page>>();
+            let mut old_pages: HashMap<String, Page> = HashMap::new();
+            for chunk in found_links
+                .iter()
+                .map(|link| Value::from(link.to_lowercase()))
+                .collect::<Vec<Value>>()
+                .chunks(MAX_PLACEHOLDERS)
+            {
+                last_query.clear();
+                last_query.push_str(&format!(
+                    "SELECT Alias.alias, Pages.id, Pages.title FROM Pages JOIN Alias ON Pages.id = Alias.id WHERE alias IN ({});",
+                    vec!["?"; chunk.len()].join(", ")
+                ));
+                old_pages.extend(timed(last_query, || {
+                    connection.exec_map(
+                        &*last_query,
+                        chunk.to_vec(),
+                        |(alias, id, title): (String, usize, String)| (alias, Page { id, title }),
+                    )
+                })?);
+            }
 
             info!(
                 "found {} old pages ({}ms)",
@@ -380,185 +972,288 @@ async fn wikicrawl(
                 .into_iter()
                 .filter(|link| !old_pages.contains_key(link))
                 .collect::<Vec<String>>();
-            let shared_count = Arc::new(Mutex::new(new_links.len()));
-            let shared_links = Arc::new(Mutex::new(new_links.into_iter()));
-            let shared_now = Arc::new(Mutex::new(Instant::now()));
-
-            let new_pages_runtime = RuntimeBuilder::new_multi_thread()
-                .worker_threads(max_new_pages.max({
-                    let count = shared_count.lock().unwrap();
-                    *count
-                }))
-                .enable_all()
-                .thread_name("wikicrawl new_pages".to_string())
-                .build()?;
-
-            let new_pages_children = (0..max_new_pages).into_iter().map(|_| {
-                let mut thread_pages: Vec<(String, Page)> = Vec::new();
-                let thread_links = Arc::clone(&shared_links);
-                let thread_count = Arc::clone(&shared_count);
-                let thread_now = Arc::clone(&shared_now);
-                new_pages_runtime.spawn(async move {
-                    while let Some(link) = {
-                        let mut links = thread_links.lock().unwrap();
-                        (*links).next()
-                    } {
-                        let page = extract_link_info_api(&link).await;
-                        let (elapsed, count) = {
-                            let now = thread_now.lock().unwrap();
-                            let mut count = thread_count.lock().unwrap();
-                            (*count).sub_assign(1);
-                            (now.elapsed().as_millis(), *count)
-                        };
-                        print!("{} pages left to find ({}ms)         \r", count, elapsed);
-                        thread_pages.push((link.to_string(), page));
+            let all_new_links_length = new_links.len();
+
+            let mut resolved_pages: Vec<(String, Page)> = Vec::new();
+            let mut uncached_links: Vec<String> = Vec::new();
+            {
+                let mut cache = shared_cache.lock().unwrap();
+                for link in new_links {
+                    match cache.get(&link.to_lowercase()) {
+                        Some(page) => resolved_pages.push((link, page.clone())),
+                        None => uncached_links.push(link),
                     }
-                    thread_pages
-                })
-            });
-            let future_new_pages = new_pages_children
-                .into_iter()
-                .map(|child| async move { child.await.unwrap() });
-            let found_pages = futures::future::join_all(future_new_pages)
-                .await
+                }
+            }
+            let cache_hits = resolved_pages.len();
+            metrics.cache_hits += cache_hits;
+            metrics.link_lookups += all_new_links_length;
+
+            // most links are exact titles, resolved by batches before the per link search
+            info!("resolving {} links by batches", uncached_links.len());
+            let batch_pages = resolve_links_batch(&uncached_links, wiki_config, &*CLIENT).await;
+            {
+                let mut cache = shared_cache.lock().unwrap();
+                for (link, page) in batch_pages.iter() {
+                    cache.put(link.to_lowercase(), page.clone());
+                }
+            }
+            info!("resolved {} links by batches", batch_pages.len());
+            let new_links = uncached_links
                 .into_iter()
-                .flatten()
-                .collect::<Vec<(String, Page)>>();
+                .filter(|link| !batch_pages.contains_key(link))
+                .collect::<Vec<String>>();
+            resolved_pages.extend(batch_pages);
 
-            new_pages_runtime.shutdown_background();
+            let new_links_length = new_links.len();
+            let new_pages_bar =
+                progress_bar(new_links_length, "finding new pages", crawl_config.quiet);
+            let now = Instant::now();
+
+            // the lookups are a stream of tasks on the runtime of the crawl, at most max_new_pages of them
+            // in flight, and a link whose turn comes after a forced stop is skipped, None, without being
+            // looked up. the tasks spread the parsing of the answers over the workers, which a stream
+            // polled by this task alone doesn't
+            let semaphore = Arc::new(Semaphore::new(max_new_pages));
+            let runtime = &context.runtime;
+            let mut lookups = stream::iter(new_links)
+                .map(|link| {
+                    let thread_link = link.clone();
+                    let thread_config = Arc::clone(&shared_config);
+                    let thread_signals = Arc::clone(signals);
+                    let thread_breaker = Arc::clone(&breaker);
+                    let thread_semaphore = Arc::clone(&semaphore);
+                    let thread_bar = new_pages_bar.clone();
+                    let lookup = runtime.spawn(async move {
+                        let _permit = thread_semaphore.acquire_owned().await.ok()?;
+                        if thread_signals.is_forced() || thread_breaker.is_open() {
+                            return None;
+                        }
+                        thread_signals.paused().await;
+                        let page =
+                            extract_link_info_api(&thread_link, &thread_config, &*CLIENT).await;
+                        thread_bar.inc(1);
+                        Some(page)
+                    });
+                    async move { (link, lookup.await) }
+                })
+                .buffer_unordered(max_new_pages);
+            let mut found_pages: Vec<(String, Page)> = Vec::new();
+            // the links left after a forced stop were never looked up
+            let mut skipped_links: HashSet<String> = HashSet::new();
+            while let Some((link, lookup)) = lookups.next().await {
+                match lookup? {
+                    Some(Ok(page)) => {
+                        shared_cache
+                            .lock()
+                            .unwrap()
+                            .put(link.to_lowercase(), page.clone());
+                        found_pages.push((link, page));
+                    }
+                    Some(Err(error)) => warn!("couldn't resolve link \"{}\": {}", link, error),
+                    None => {
+                        skipped_links.insert(link);
+                    }
+                }
+            }
+            found_pages.extend(resolved_pages);
+
+            new_pages_bar.finish_and_clear();
 
             info!(
-                "found {} pages ({}ms)               ",
+                "found {} pages ({}ms)",
                 found_pages.len(),
-                shared_now.lock().unwrap().elapsed().as_millis()
+                now.elapsed().as_millis()
             );
+            if all_new_links_length > 0 {
+                info!(
+                    "link cache hit rate: {}/{} ({}%)",
+                    cache_hits,
+                    all_new_links_length,
+                    cache_hits * 100 / all_new_links_length
+                );
+            }
 
             // split found_pages into new_pages and found_again_pages using the connection
-            let found_again_pages_ids = if found_pages.is_empty() {
-                Vec::new()
-            } else {
+            let mut found_again_pages_ids: HashSet<usize> = HashSet::new();
+            for chunk in found_pages
+                .iter()
+                .map(|(_, page)| page.id)
+                .collect::<HashSet<usize>>()
+                .into_iter()
+                .map(Value::from)
+                .collect::<Vec<Value>>()
+                .chunks(crawl_config.insert_chunk_size.min(MAX_PLACEHOLDERS))
+            {
                 last_query.clear();
                 last_query.push_str(&format!(
                     "SELECT id FROM Pages WHERE id IN ({});",
-                    found_pages
-                        .iter()
-                        .map(|(_, page)| page.id.to_string())
-                        .collect::<Vec<String>>()
-                        .join(","),
+                    vec!["?"; chunk.len()].join(", ")
                 ));
-                connection.query_map(&last_query, |id: usize| id)?
-            };
+                found_again_pages_ids.extend(timed(last_query, || {
+                    connection.exec_map(&*last_query, chunk.to_vec(), |id: usize| id)
+                })?);
+            }
 
             let (found_again_pages, new_pages): (HashMap<String, Page>, HashMap<String, Page>) =
                 found_pages
                     .into_iter()
-                    .filter(|(_, page)| page.id != 0)
                     .partition(|(_, page)| found_again_pages_ids.contains(&page.id));
 
-            old_pages.extend(found_again_pages.into_iter());
+            old_pages.extend(found_again_pages);
 
-            let unique_new_pages = new_pages
-                .iter()
-                .map(|(_, page)| page)
-                .collect::<HashSet<&Page>>();
-            let added_pages = unique_new_pages.len();
+            let unique_new_pages = new_pages.values().collect::<HashSet<&Page>>();
 
             info!("found {} new pages", unique_new_pages.len(),);
             info!("found again {} old pages", old_pages.len());
 
-            // insert new pages
-            if added_pages > 0 {
-                total_info.pages += added_pages;
-                last_query.clear();
-                last_query.push_str(&format!(
-                    "INSERT INTO Pages (id, title) VALUES {};",
-                    unique_new_pages
-                        .into_iter()
-                        .map(|page| {
-                            format!("({}, \"{}\")", page.id, format_link_for_mysql(&page.title))
-                        })
-                        .collect::<Vec<String>>()
-                        .join(","),
-                ));
-                info!("inserting new pages");
-                connection.query_drop(&last_query)?;
-                info!("inserted {} new pages", added_pages);
+            let mut wikidata_ids = HashMap::new();
+            if wiki_config.wikidata && !unique_new_pages.is_empty() {
+                info!("fetching the wikidata ids of the new pages");
+                let ids = unique_new_pages
+                    .iter()
+                    .map(|page| page.id)
+                    .collect::<Vec<usize>>();
+                wikidata_ids = fetch_wikidata_ids(&ids, wiki_config, &*CLIENT).await;
             }
-
-            // insert aliases of new Pages
-            if new_pages.len() > 0 {
-                last_query.clear();
-                last_query.push_str(&format!(
-                    "INSERT INTO Alias (alias, id) VALUES {};",
-                    new_pages
-                        .iter()
-                        .map(|(alias, page)| {
-                            format!("(\"{}\",{})", format_link_for_mysql(alias), page.id)
-                        })
-                        .collect::<Vec<String>>()
-                        .join(","),
-                ));
-                info!("inserting aliases of found pages");
-                connection.query_drop(&last_query)?;
-                info!("inserted {} aliases", new_pages.len());
+            let mut summaries = HashMap::new();
+            if wiki_config.summaries && !unique_new_pages.is_empty() {
+                info!("fetching the summaries of the new pages");
+                let pages = unique_new_pages.iter().copied().collect::<Vec<&Page>>();
+                summaries = fetch_summaries(&pages, wiki_config, &*CLIENT).await;
             }
+            Some((old_pages, new_pages, skipped_links, wikidata_ids, summaries))
+        };
 
-            // transform the results array into an array of relations between pages
-            info!("generating relations ");
-            let relations_found = results
+        let mut lang_links = HashMap::new();
+        if wiki_config.lang_links && !results.is_empty() {
+            info!("fetching the language links of the explored pages");
+            let ids = results
                 .iter()
-                .map(|(page, links)| {
-                    links
+                .map(|(page, _)| page.id)
+                .collect::<Vec<usize>>();
+            lang_links = fetch_lang_links(&ids, wiki_config, &*CLIENT).await;
+        }
+
+        // every write of the batch is committed at once, a failure leaving the database as before the batch
+        let mut transaction = connection.start_transaction(TxOpts::default())?;
+        // the recrawled pages were already explored, only the others add to the explored pages
+        let mut unexplored_pages_ids: HashSet<usize> = HashSet::new();
+        for chunk in exploring_pages
+            .iter()
+            .map(|page| Value::from(page.id))
+            .collect::<Vec<Value>>()
+            .chunks(crawl_config.insert_chunk_size.min(MAX_PLACEHOLDERS))
+        {
+            last_query.clear();
+            last_query.push_str(&format!(
+                "SELECT id FROM Pages WHERE explored = FALSE AND id IN ({});",
+                vec!["?"; chunk.len()].join(", ")
+            ));
+            unexplored_pages_ids.extend(timed(last_query, || {
+                transaction.exec_map(&*last_query, chunk.to_vec(), |id: usize| id)
+            })?);
+        }
+        let mut added_pages = 0;
+        if let Some((old_pages, new_pages, skipped_links, wikidata_ids, summaries)) = &lookups {
+            added_pages = new_pages.values().collect::<HashSet<&Page>>().len();
+            save_new_pages(
+                last_query,
+                &mut transaction,
+                new_pages,
+                crawl_config.insert_chunk_size,
+            )?;
+            if !wikidata_ids.is_empty() {
+                save_wikidata_ids(last_query, &mut transaction, wikidata_ids)?;
+                info!("saved {} wikidata ids", wikidata_ids.len());
+            }
+            if !summaries.is_empty() {
+                save_summaries(last_query, &mut transaction, summaries)?;
+                info!("saved {} summaries", summaries.len());
+            }
+
+            // with WIKICRAWL_JSONL_ONLY the new pages are still saved as pages to explore, their links only go to the jsonl file
+            if !crawl_config.jsonl_only {
+                info!("inserting the relations of each page");
+                for (page, explored_page) in results.iter() {
+                    // a page with skipped links keeps its links but is explored again by the next run
+                    let complete = explored_page
+                        .links
                         .iter()
-                        .filter_map(|(link, display)| {
-                            let linked = old_pages.get(link).or(new_pages.get(link));
-                            linked.map(|link| Link {
-                                linker: page.id,
-                                linked: link.id,
-                                display: display,
-                            })
-                        })
-                        .collect::<HashSet<Link>>()
-                })
-                .flatten()
-                .collect::<HashSet<Link>>();
-            info!("generated {} relations", relations_found.len());
+                        .all(|(link, _)| !skipped_links.contains(link));
+                    batch_links += save_explored_page(
+                        last_query,
+                        &mut transaction,
+                        page,
+                        explored_page,
+                        |link| old_pages.get(link).or(new_pages.get(link)),
+                        complete,
+                        crawl_config.insert_chunk_size,
+                    )?;
+                    if !complete {
+                        skipped_pages.insert(page.id);
+                    }
+                }
+                info!("inserted {} relations", batch_links);
+            }
+        }
+        if !lang_links.is_empty() {
+            save_lang_links(
+                last_query,
+                &mut transaction,
+                &lang_links,
+                crawl_config.insert_chunk_size,
+            )?;
+            info!("saved the language links of {} pages", lang_links.len());
+        }
 
-            // insert the new relations
+        // mark the remaining pages as explored
+        let saved_pages_id = match lookups {
+            Some(_) if !crawl_config.jsonl_only => results
+                .iter()
+                .map(|(page, _)| page.id)
+                .collect::<HashSet<usize>>(),
+            _ => HashSet::new(),
+        };
+        let unsaved_pages = exploring_pages
+            .iter()
+            .filter(|page| !saved_pages_id.contains(&page.id))
+            .collect::<Vec<&Page>>();
+        if !unsaved_pages.is_empty() {
             last_query.clear();
             last_query.push_str(&format!(
-                "INSERT INTO Links (linker, linked, display) VALUES {};",
-                relations_found
+                "UPDATE Pages SET explored = TRUE, explored_at = NOW() WHERE id IN ({});",
+                unsaved_pages
                     .iter()
-                    .map(|link| format!(
-                        "({},{},\"{}\")",
-                        link.linker,
-                        link.linked,
-                        format_link_for_mysql(&link.display)
-                    ))
+                    .map(|page| page.id.to_string())
                     .collect::<Vec<String>>()
                     .join(", "),
             ));
-            info!("inserting the relations ");
-            connection.query_drop(&last_query)?;
-            info!("inserted {} relations", relations_found.len());
-            total_info.links += relations_found.len();
+            info!("marking pages as explored ");
+            timed(last_query, || transaction.query_drop(&last_query))?;
+        }
+        info!("committing the batch");
+        transaction.commit()?;
+        // written once committed, so that a rolled back batch explored again doesn't write its links twice
+        if let Some(jsonl_out) = &mut jsonl_out {
+            info!("writing the extracted links");
+            write_jsonl(jsonl_out, &results)?;
         }
+        exploring_pages.clear();
+        total_info.pages += added_pages;
+        total_info.links += batch_links;
+        let explored_length = unexplored_length - skipped_pages.len();
+        info!("explored {} pages", explored_length);
+        // explored follows the explored flag of the database, run_explored counts the explorations of the
+        // run, the recrawled pages included, for WIKICRAWL_MAX_PAGES
+        total_info.explored += unexplored_pages_ids
+            .iter()
+            .filter(|id| !skipped_pages.contains(id))
+            .count();
+        total_info.run_explored += explored_length;
 
-        // mark as explored
-        last_query.clear();
-        last_query.push_str(&format!(
-            "UPDATE Pages SET explored = TRUE WHERE id IN ({});",
-            exploring_pages
-                .iter()
-                .map(|page| page.id.to_string())
-                .collect::<Vec<String>>()
-                .join(", "),
-        ));
-        info!("marking pages as explored ");
-        connection.query_drop(&last_query)?;
-        info!("explored {} pages", unexplored_length);
-        total_info.explored += unexplored_length;
+        info!("saving the crawl state");
+        save_crawl_state(last_query, connection, total_info)?;
 
         info!("");
         info!(
@@ -567,53 +1262,682 @@ async fn wikicrawl(
         );
         info!("found {} pages", total_info.pages);
         info!("listed {} links", total_info.links);
+        let batch_time = batch_now.elapsed();
+        batch_rates.push_back((explored_length, batch_links, batch_time));
+        if batch_rates.len() > RATE_WINDOW {
+            batch_rates.pop_front();
+        }
+        let (window_pages, window_links, window_time) = batch_rates.iter().fold(
+            (0, 0, Duration::ZERO),
+            |(pages, links, time), (batch_pages, batch_links, batch_time)| {
+                (pages + batch_pages, links + batch_links, time + *batch_time)
+            },
+        );
+        info!(
+            "batch took {} ms: {:.1} pages/s, {:.1} links/s (last {} batches: {:.1} pages/s, {:.1} links/s)",
+            batch_time.as_millis(),
+            explored_length as f64 / batch_time.as_secs_f64(),
+            batch_links as f64 / batch_time.as_secs_f64(),
+            batch_rates.len(),
+            window_pages as f64 / window_time.as_secs_f64(),
+            window_links as f64 / window_time.as_secs_f64()
+        );
+        metrics.pages_per_second = window_pages as f64 / window_time.as_secs_f64();
+        metrics.links_per_second = window_links as f64 / window_time.as_secs_f64();
+        if let Some(metrics_file) = &crawl_config.metrics_file {
+            if let Err(error) = write_metrics(metrics_file, &metrics, total_info) {
+                warn!("couldn't write the metrics to {}: {}", metrics_file, error);
+            }
+        }
+        match (baseline_latency, LATENCY.average()) {
+            (None, latency) => baseline_latency = latency,
+            (Some(baseline), Some(latency))
+                if crawl_config.latency_alert > 0.0
+                    && latency > baseline.mul_f64(crawl_config.latency_alert) =>
+            {
+                warn!(
+                    "requests take {} ms on average against {} ms after the first batch, wikimedia may be throttling the crawl, consider lowering WIKICRAWL_MAX_RPS",
+                    latency.as_millis(),
+                    baseline.as_millis()
+                );
+            }
+            _ => {}
+        }
+        // every batch finds new pages, so the estimate moves with the graph instead of counting down
+        if window_pages > 0 {
+            let remaining_pages = remaining_pages.saturating_sub(explored_length);
+            let remaining_secs =
+                (remaining_pages as f64 * window_time.as_secs_f64() / window_pages as f64) as u64;
+            info!(
+                "{} pages left to explore, about {}h{:02}m{:02}s at this rate if no new page were found",
+                remaining_pages,
+                remaining_secs / 3600,
+                remaining_secs % 3600 / 60,
+                remaining_secs % 60
+            );
+        }
         info!("");
+
+        // the limits are only checked between batches, so the last batch is always complete
+        if crawl_config.max_pages > 0 && total_info.run_explored >= crawl_config.max_pages {
+            info!(
+                "explored {} pages during this run, reaching the limit of {}",
+                total_info.run_explored, crawl_config.max_pages
+            );
+            break;
+        }
+        if !crawl_config.max_duration.is_zero()
+            && total_info.started_at.elapsed() >= crawl_config.max_duration
+        {
+            info!(
+                "ran for {} s, reaching the limit of {} s",
+                total_info.started_at.elapsed().as_secs(),
+                crawl_config.max_duration.as_secs()
+            );
+            break;
+        }
+
+        batch_size = adapt_batch_size(
+            batch_size,
+            batch_time,
+            breaker.failing() * 100 / unexplored_length,
+            crawl_config,
+        );
+        info!("next batch explores up to {} pages", batch_size);
+
+        if breaker.is_open() {
+            warn!(
+                "{} of the {} pages of the batch failed, waiting {} s before the next batch",
+                breaker.failing(),
+                unexplored_length,
+                crawl_config.failure_cooldown.as_secs()
+            );
+            let cooldown_end = Instant::now() + crawl_config.failure_cooldown;
+            while Instant::now() < cooldown_end && !signals.is_cancelled() {
+                time::sleep(PAUSE_POLL).await;
+            }
+        }
     }
 
-    return Ok(());
+    release_claims(last_query, connection, &total_info.run_id)?;
+    Ok(())
 }
 
-async fn explore(page: &Page) -> Result<Vec<(String, String)>, Box<dyn Error>> {
-    let request = format!("https://fr.m.wikipedia.org/?curid={}", page.id);
+// halves the batch after a slow or failing one, grows it by half after a fast one
+fn adapt_batch_size(
+    batch_size: usize,
+    batch_time: Duration,
+    error_rate: usize,
+    crawl_config: &CrawlConfig,
+) -> usize {
+    let batch_size = if error_rate > ADAPT_ERROR_RATE || batch_time > crawl_config.target_batch_time
+    {
+        batch_size / 2
+    } else if batch_time < crawl_config.target_batch_time / 2 {
+        batch_size + batch_size.div_ceil(2)
+    } else {
+        batch_size
+    };
+    batch_size.clamp(crawl_config.min_batch_pages, crawl_config.max_batch_pages)
+}
 
-    let mut retry_cooldown = RETRY_COOLDOWN.clone();
-    let delta_t = Duration::from_secs(1);
-    loop {
-        retry_cooldown.add_assign(delta_t);
-        let body = CLIENT
-            .get(request.clone())
-            .send()
-            .await
+// the claims of the last batch, including its skipped pages. the claims left by a crash
+// are taken over by the other machines once they are older than the claim timeout
+fn release_claims(
+    last_query: &mut String,
+    connection: &mut PooledConn,
+    run_id: &str,
+) -> Result<(), WikicrawlError> {
+    last_query.clear();
+    last_query
+        .push_str("UPDATE Pages SET claimed_by = NULL, claimed_at = NULL WHERE claimed_by = ?;");
+    timed(last_query, || connection.exec_drop(&*last_query, (run_id,)))?;
+    Ok(())
+}
+
+// inserts the pages found by a batch and their aliases, new_pages being keyed by alias
+fn save_new_pages(
+    last_query: &mut String,
+    connection: &mut impl Queryable,
+    new_pages: &HashMap<String, Page>,
+    chunk_size: usize,
+) -> Result<(), mysql::Error> {
+    let unique_new_pages = new_pages.values().collect::<HashSet<&Page>>();
+    if !unique_new_pages.is_empty() {
+        info!("inserting new pages");
+        insert_chunked(
+            last_query,
+            connection,
+            "INSERT INTO Pages (id, title, original_title, created_at) VALUES",
+            "(?, ?, ?, NOW())",
+            unique_new_pages
+                .iter()
+                .map(|page| {
+                    vec![
+                        page.id.into(),
+                        page.title.to_lowercase().into(),
+                        page.title.clone().into(),
+                    ]
+                })
+                .collect(),
+            "",
+            chunk_size,
+        )?;
+        info!("inserted {} new pages", unique_new_pages.len());
+    }
+
+    if !new_pages.is_empty() {
+        info!("inserting aliases of found pages");
+        insert_chunked(
+            last_query,
+            connection,
+            "INSERT INTO Alias (alias, id) VALUES",
+            "(?, ?)",
+            new_pages
+                .iter()
+                .map(|(alias, page)| vec![alias.to_lowercase().into(), page.id.into()])
+                .collect(),
+            // an alias seen again after a page move points to the new page
+            "ON DUPLICATE KEY UPDATE id = VALUES(id)",
+            chunk_size,
+        )?;
+        info!("inserted {} aliases", new_pages.len());
+    }
+    Ok(())
+}
+
+fn save_wikidata_ids(
+    last_query: &mut String,
+    connection: &mut impl Queryable,
+    wikidata_ids: &HashMap<usize, String>,
+) -> Result<(), mysql::Error> {
+    last_query.clear();
+    last_query.push_str("UPDATE Pages SET wikidata_id = ? WHERE id = ?;");
+    timed(last_query, || {
+        connection.exec_batch(
+            &*last_query,
+            wikidata_ids
+                .iter()
+                .map(|(id, wikidata_id)| (wikidata_id, id)),
+        )
+    })
+}
+
+fn save_summaries(
+    last_query: &mut String,
+    connection: &mut impl Queryable,
+    summaries: &HashMap<usize, String>,
+) -> Result<(), mysql::Error> {
+    last_query.clear();
+    last_query.push_str("UPDATE Pages SET summary = ? WHERE id = ?;");
+    timed(last_query, || {
+        connection.exec_batch(
+            &*last_query,
+            summaries.iter().map(|(id, summary)| (summary, id)),
+        )
+    })
+}
+
+// the links of a page from an older run are replaced by the new ones of the same languages
+fn save_lang_links(
+    last_query: &mut String,
+    connection: &mut impl Queryable,
+    lang_links: &HashMap<usize, Vec<(String, String)>>,
+    chunk_size: usize,
+) -> Result<(), mysql::Error> {
+    insert_chunked(
+        last_query,
+        connection,
+        "INSERT INTO LangLinks (id, lang, title) VALUES",
+        "(?, ?, ?)",
+        lang_links
+            .iter()
+            .flat_map(|(id, links)| {
+                links.iter().map(|(lang, title)| {
+                    vec![
+                        (*id).into(),
+                        lang.as_str().into(),
+                        title.to_lowercase().into(),
+                    ]
+                })
+            })
+            .collect(),
+        "ON DUPLICATE KEY UPDATE title = VALUES(title)",
+        chunk_size,
+    )
+}
+
+// inserts the relations and categories of a page and marks it as explored when complete, in the
+// transaction of the batch. resolve gives the page of a link, the links without one are dropped, returns the number of relations
+fn save_explored_page<'a>(
+    last_query: &mut String,
+    transaction: &mut Transaction,
+    page: &Page,
+    explored_page: &ExploredPage,
+    resolve: impl Fn(&String) -> Option<&'a Page>,
+    complete: bool,
+    chunk_size: usize,
+) -> Result<usize, mysql::Error> {
+    let resolved_links = explored_page
+        .links
+        .iter()
+        .filter_map(|(link, display)| resolve(link).map(|link| (link.id, display)))
+        .collect::<Vec<(usize, &String)>>();
+    // counted before the set keeps a single link per linked page
+    let mut anchors: HashMap<usize, HashSet<String>> = HashMap::new();
+    for (linked, display) in &resolved_links {
+        anchors
+            .entry(*linked)
+            .or_default()
+            .insert(display.to_lowercase());
+    }
+    let relations_found = resolved_links
+        .into_iter()
+        .map(|(linked, display)| Link {
+            linker: page.id,
+            linked,
+            display,
+            weight: anchors[&linked].len(),
+        })
+        .collect::<HashSet<Link>>();
+
+    insert_chunked(
+        last_query,
+        transaction,
+        "INSERT INTO Links (linker, linked, display, weight) VALUES",
+        "(?, ?, ?, ?)",
+        relations_found
+            .iter()
+            .map(|link| {
+                vec![
+                    link.linker.into(),
+                    link.linked.into(),
+                    link.display.to_lowercase().into(),
+                    link.weight.into(),
+                ]
+            })
+            .collect(),
+        // links from an old run of the same page are overwritten instead of deleted beforehand
+        "ON DUPLICATE KEY UPDATE display = VALUES(display), weight = VALUES(weight)",
+        chunk_size,
+    )?;
+    insert_chunked(
+        last_query,
+        transaction,
+        "INSERT IGNORE INTO Categories (id, category) VALUES",
+        "(?, ?)",
+        explored_page
+            .categories
+            .iter()
+            .map(|category| vec![page.id.into(), category.to_lowercase().into()])
+            .collect(),
+        "",
+        chunk_size,
+    )?;
+    if complete {
+        last_query.clear();
+        last_query.push_str(&format!(
+            "UPDATE Pages SET explored = TRUE, explored_at = NOW(), degree = {} WHERE id = {};",
+            relations_found.len(),
+            page.id
+        ));
+        timed(last_query, || transaction.query_drop(&last_query))?;
+    }
+    Ok(relations_found.len())
+}
+
+// in the text format of prometheus, written next to the file then renamed so that a scrape never reads
+// half of it
+fn write_metrics(
+    metrics_file: &str,
+    metrics: &Metrics,
+    total_info: &TotalInfo,
+) -> Result<(), std::io::Error> {
+    let values = [
+        (
+            "wikicrawl_explored_pages",
+            "gauge",
+            "explored pages in the database",
+            total_info.explored as f64,
+        ),
+        (
+            "wikicrawl_bugged_pages",
+            "gauge",
+            "bugged pages in the database",
+            total_info.bugged as f64,
+        ),
+        (
+            "wikicrawl_pages",
+            "gauge",
+            "pages in the database",
+            total_info.pages as f64,
+        ),
+        (
+            "wikicrawl_links",
+            "gauge",
+            "links in the database",
+            total_info.links as f64,
+        ),
+        (
+            "wikicrawl_run_explored_pages_total",
+            "counter",
+            "pages explored by this run",
+            total_info.run_explored as f64,
+        ),
+        (
+            "wikicrawl_requests_total",
+            "counter",
+            "requests sent to wikipedia, without the cached ones",
+            LATENCY.requests() as f64,
+        ),
+        (
+            "wikicrawl_link_lookups_total",
+            "counter",
+            "links missing from the database looked up",
+            metrics.link_lookups as f64,
+        ),
+        (
+            "wikicrawl_link_cache_hits_total",
+            "counter",
+            "link lookups answered by the link cache",
+            metrics.cache_hits as f64,
+        ),
+        (
+            "wikicrawl_pages_per_second",
+            "gauge",
+            "explored pages per second over the last batches",
+            metrics.pages_per_second,
+        ),
+        (
+            "wikicrawl_links_per_second",
+            "gauge",
+            "inserted links per second over the last batches",
+            metrics.links_per_second,
+        ),
+    ];
+    let mut content = String::new();
+    for (name, kind, help, value) in values {
+        content.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+            name, help, name, kind, name, value
+        ));
+    }
+    let temporary_file = format!("{}.tmp", metrics_file);
+    std::fs::write(&temporary_file, content)?;
+    std::fs::rename(temporary_file, metrics_file)
+}
+
+// one line per extracted link, with the link as found in the page since it isn't resolved yet
+fn write_jsonl(
+    jsonl_out: &mut BufWriter<File>,
+    results: &[(Page, ExploredPage)],
+) -> Result<(), std::io::Error> {
+    for (page, explored_page) in results {
+        for (link, display) in &explored_page.links {
+            let line = serde_json::json!({
+                "linker_id": page.id,
+                "linked_slug": link,
+                "display": display,
+            });
+            writeln!(jsonl_out, "{}", line)?;
+        }
+    }
+    jsonl_out.flush()
+}
+
+// marks the pages as bugged and records their last error in BuggedPages, counting their failures
+fn save_bugged_pages(
+    last_query: &mut String,
+    connection: &mut PooledConn,
+    bugged_pages: &[(Page, String)],
+    chunk_size: usize,
+) -> Result<(), mysql::Error> {
+    for chunk in bugged_pages
+        .iter()
+        .map(|(page, _error)| Value::from(page.id))
+        .collect::<Vec<Value>>()
+        .chunks(chunk_size.min(MAX_PLACEHOLDERS))
+    {
+        last_query.clear();
+        last_query.push_str(&format!(
+            "UPDATE Pages SET bugged = TRUE, bugged_at = CURRENT_TIMESTAMP WHERE id IN ({});",
+            vec!["?"; chunk.len()].join(", ")
+        ));
+        timed(last_query, || {
+            connection.exec_drop(&*last_query, chunk.to_vec())
+        })?;
+    }
+    insert_chunked(
+        last_query,
+        connection,
+        "INSERT INTO BuggedPages (id, error, failures, failed_at) VALUES",
+        "(?, ?, 1, NOW())",
+        bugged_pages
+            .iter()
+            .map(|(page, error)| vec![page.id.into(), error.as_str().into()])
+            .collect(),
+        "ON DUPLICATE KEY UPDATE error = VALUES(error), failures = failures + 1, failed_at = NOW()",
+        chunk_size,
+    )
+}
+
+// one row per run, updated after each batch so the progress of a run can be followed from the database
+fn save_crawl_state(
+    last_query: &mut String,
+    connection: &mut PooledConn,
+    total_info: &TotalInfo,
+) -> Result<(), mysql::Error> {
+    last_query.clear();
+    last_query.push_str(
+        "INSERT INTO CrawlState (run_id, explored, bugged, pages, links) VALUES (:run_id, :explored, :bugged, :pages, :links) ON DUPLICATE KEY UPDATE explored = VALUES(explored), bugged = VALUES(bugged), pages = VALUES(pages), links = VALUES(links), updated_at = CURRENT_TIMESTAMP;",
+    );
+    timed(last_query, || {
+        connection.exec_drop(
+            &last_query,
+            params! {
+                "run_id" => &total_info.run_id,
+                "explored" => total_info.explored,
+                "bugged" => total_info.bugged,
+                "pages" => total_info.pages,
+                "links" => total_info.links,
+            },
+        )
+    })
+}
+
+// the pages explored before explored_at existed have no date and are considered stale
+fn to_explore_condition(crawl_config: &CrawlConfig) -> String {
+    if crawl_config.recrawl_days == 0 {
+        "explored = false".to_string()
+    } else {
+        format!(
+            "(explored = false OR explored_at IS NULL OR explored_at < NOW() - INTERVAL {} DAY)",
+            crawl_config.recrawl_days
+        )
+    }
+}
+
+fn id_range_condition(crawl_config: &CrawlConfig) -> String {
+    let mut condition = String::new();
+    if crawl_config.start_id > 0 {
+        condition.push_str(&format!(" AND id >= {}", crawl_config.start_id));
+    }
+    if crawl_config.max_id > 0 {
+        condition.push_str(&format!(" AND id <= {}", crawl_config.max_id));
+    }
+    condition
+}
+
+// an empty database has nothing to explore, the crawl starts from the seed page
+async fn seed(
+    last_query: &mut String,
+    connection: &mut PooledConn,
+    crawl_config: &CrawlConfig,
+    wiki_config: &WikiConfig,
+) -> Result<(), WikicrawlError> {
+    info!(
+        "no pages found, seeding with \"{}\"",
+        crawl_config.seed_title
+    );
+    let seed_link = crawl_config.seed_title.to_lowercase();
+    let page = extract_link_info_api(&seed_link, wiki_config, &*CLIENT).await?;
+
+    last_query.clear();
+    last_query.push_str(
+        "INSERT INTO Pages (id, title, original_title, explored, created_at) VALUES (:id, :title, :original_title, FALSE, NOW());",
+    );
+    timed(last_query, || {
+        connection.exec_drop(
+            &last_query,
+            params! {
+                "id" => page.id,
+                "title" => page.title.to_lowercase(),
+                "original_title" => &page.title,
+            },
+        )
+    })?;
+    last_query.clear();
+    last_query.push_str("INSERT INTO Alias (alias, id) VALUES (:alias, :id);");
+    timed(last_query, || {
+        connection.exec_drop(
+            &last_query,
+            params! { "alias" => &seed_link, "id" => page.id },
+        )
+    })?;
+    if wiki_config.wikidata {
+        let wikidata_ids = fetch_wikidata_ids(&[page.id], wiki_config, &*CLIENT).await;
+        save_wikidata_ids(last_query, connection, &wikidata_ids)?;
+    }
+    if wiki_config.summaries {
+        let summaries = fetch_summaries(&[&page], wiki_config, &*CLIENT).await;
+        save_summaries(last_query, connection, &summaries)?;
+    }
+    info!("seeded with Page {}", page);
+    Ok(())
+}
+
+// the bars are drawn on stdout between two batches of logs, and hidden when it isn't a terminal
+fn progress_bar(length: usize, message: &'static str, quiet: bool) -> ProgressBar {
+    if quiet || !std::io::stdout().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::with_draw_target(Some(length as u64), ProgressDrawTarget::stdout());
+    bar.set_style(
+        ProgressStyle::with_template("{msg} [{bar:40}] {pos}/{len} ({percent}%) {elapsed}")
             .unwrap()
-            .text()
-            .await
-            .unwrap();
+            .progress_chars("=> "),
+    );
+    bar.set_message(message);
+    bar
+}
 
-        if body.contains("<title>Wikimedia Error</title>") {
-            warn!("exploring {} throwed wikimedia error", page);
-            time::sleep(retry_cooldown).await;
-            continue;
+// the values of each row are bound to the placeholders of row instead of escaped into the query,
+// and the rows are split into several queries so none exceeds max_allowed_packet nor the placeholder limit
+fn insert_chunked(
+    last_query: &mut String,
+    connection: &mut impl Queryable,
+    insert: &str,
+    row: &str,
+    values: Vec<Vec<Value>>,
+    suffix: &str,
+    chunk_size: usize,
+) -> Result<(), mysql::Error> {
+    let chunk_size = chunk_size.min(MAX_PLACEHOLDERS / row.matches('?').count().max(1));
+    for chunk in values.chunks(chunk_size) {
+        last_query.clear();
+        last_query.push_str(&format!(
+            "{} {} {};",
+            insert,
+            vec![row; chunk.len()].join(","),
+            suffix
+        ));
+        timed(last_query, || {
+            connection.exec_drop(&*last_query, chunk.concat())
+        })?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn explore(
+    page: &Page,
+    config: &WikiConfig,
+    fetcher: &impl WikiFetcher,
+    breaker: &CircuitBreaker,
+) -> Result<ExploredPage, Box<dyn Error>> {
+    let request = match config.link_source {
+        LinkSource::Html => format!("https://{}/?curid={}", config.host, page.id),
+        LinkSource::Api => format!(
+            "https://{}/w/api.php?action=parse&format=json&formatversion=2&prop=links%7Ccategories&pageid={}",
+            config.host, page.id
+        ),
+    };
+
+    let mut backoff = Backoff::new(config.max_backoff);
+    for _ in 0..MAX_RETRIES {
+        if breaker.is_open() {
+            return Err("too many pages of the batch failed".into());
         }
+        let body = match fetcher.fetch(&request).await {
+            Ok(body) => body,
+            Err(error) if error.is_timeout() => {
+                warn!("exploring {} timed out", page);
+                breaker.record_failure(page);
+                time::sleep(backoff.next_delay()).await;
+                continue;
+            }
+            // the server tells how long to wait, the backoff only guesses it without Retry-After
+            Err(FetchError::Throttled(delay)) => {
+                warn!("exploring {} throttled", page);
+                breaker.record_failure(page);
+                // the delay asked by the server is capped by WIKICRAWL_MAX_BACKOFF
+                let delay = delay.map_or_else(
+                    || backoff.next_delay(),
+                    |delay| delay.min(config.max_backoff),
+                );
+                time::sleep(delay).await;
+                continue;
+            }
+            Err(error) => return Err(Box::new(error)),
+        };
 
-        let found_links = EXPLORE_REGEX
-            .captures_iter(body.as_str())
-            .map(|captures| {
-                let link = decode(captures.get(1).unwrap().as_str())
-                    .unwrap()
-                    .into_owned()
-                    .to_ascii_lowercase();
-                let display = captures.get(2).unwrap().as_str().to_string();
-                (link, display)
-            })
-            .collect::<HashSet<(String, String)>>();
+        let found_links = match config.link_source {
+            LinkSource::Html => {
+                if body.contains("<title>Wikimedia Error</title>") {
+                    warn!("exploring {} throwed wikimedia error", page);
+                    breaker.record_failure(page);
+                    time::sleep(backoff.next_delay()).await;
+                    continue;
+                }
+                html_links(&body)
+            }
+            LinkSource::Api => match parse_api_links(&body) {
+                Some(links) => links,
+                None => {
+                    warn!("exploring {} throwed api error", page);
+                    breaker.record_failure(page);
+                    time::sleep(backoff.next_delay()).await;
+                    continue;
+                }
+            },
+        };
 
+        // the api gives the categories apart, the html among the links with the category namespace
+        let categories = match config.link_source {
+            LinkSource::Html => found_links
+                .iter()
+                .filter_map(|(link, _display)| link.strip_prefix(&config.category_prefix))
+                .map(|category| category.to_string())
+                .collect::<HashSet<String>>(),
+            LinkSource::Api => parse_api_categories(&body),
+        };
+
+        let found_links = match config.link_source {
+            LinkSource::Html if config.article_links_only => html_links(article_text(&body)),
+            _ => found_links,
+        };
         let filtered_links = found_links
             .into_iter()
-            .filter(|(link, _display)| {
-                !WIKIPEDIA_NAMESPACES
-                    .iter()
-                    .any(|namespace| link.starts_with(namespace))
-            })
+            .filter(|(link, _display)| config.is_article_link(link))
             .collect::<Vec<(String, String)>>();
 
         if filtered_links.is_empty() {
@@ -623,17 +1947,89 @@ async fn explore(page: &Page) -> Result<Vec<(String, String)>, Box<dyn Error>> {
             );
         }
 
-        return Ok(filtered_links);
+        return Ok(ExploredPage {
+            links: filtered_links,
+            categories: categories.into_iter().collect(),
+        });
     }
+
+    Err(Box::from(format!(
+        "exploring {} still throwed wikimedia errors or timed out after {} attempts",
+        page, MAX_RETRIES
+    )))
+}
+
+fn html_links(html: &str) -> HashSet<(String, String)> {
+    EXPLORE_REGEX
+        .captures_iter(html)
+        // the anchors holding only an image have no text to display
+        .filter_map(|captures| {
+            let link = normalize_title(
+                &decode(captures.get(1).unwrap().as_str())
+                    .unwrap()
+                    .to_ascii_lowercase(),
+            );
+            let display =
+                normalize_title(&TAG_REGEX.replace_all(captures.get(2).unwrap().as_str(), ""));
+            (!display.trim().is_empty()).then_some((link, display))
+        })
+        .collect()
+}
+
+// the page from the start of its mw-parser-output div to its first navbox or its footer, which come
+// after the article text. the whole page is kept when the markup doesn't have the div
+fn article_text(body: &str) -> &str {
+    let Some(start) = body.find("mw-parser-output") else {
+        return body;
+    };
+    let end = ARTICLE_END_MARKERS
+        .iter()
+        .filter_map(|marker| body[start..].find(marker))
+        .min()
+        .map_or(body.len(), |end| start + end);
+    &body[start..end]
 }
 
-fn setup_logs() -> Result<(), Box<dyn Error>> {
+// the links are formatted like the html hrefs, the api doesn't give their displayed text so it is their title
+fn parse_api_links(body: &str) -> Option<HashSet<(String, String)>> {
+    let json = serde_json::from_str::<serde_json::Value>(body).ok()?;
+    let links = json["parse"]["links"].as_array()?;
+    Some(
+        links
+            .iter()
+            .filter(|link| link["ns"].as_i64() == Some(0))
+            .filter_map(|link| link["title"].as_str())
+            .map(normalize_title)
+            .map(|title| (title.replace(' ', "_").to_ascii_lowercase(), title))
+            .collect(),
+    )
+}
+
+// the categories are formatted like the html hrefs, without their namespace
+fn parse_api_categories(body: &str) -> HashSet<String> {
+    let json = serde_json::from_str::<serde_json::Value>(body).unwrap_or_default();
+    json["parse"]["categories"]
+        .as_array()
+        .map(|categories| {
+            categories
+                .iter()
+                .filter_map(|category| category["category"].as_str())
+                .map(|category| {
+                    normalize_title(category)
+                        .replace(' ', "_")
+                        .to_ascii_lowercase()
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn setup_logs(crawl_config: &CrawlConfig, run_id: &str) -> Result<(), std::io::Error> {
     std::fs::DirBuilder::new().recursive(true).create("logs")?;
     let log_pattern = "{d(%Y-%m-%d_%H:%M:%S)}-[{l}]: {m}{n}";
 
     let mut log_name = Local::now().format("%Y-%m-%d").to_string();
     let this_day_logs = std::fs::read_dir("logs")?
-        .into_iter()
         .filter_map(|file| match file {
             Ok(entry) => match entry.file_name().into_string() {
                 Ok(name) => {
@@ -650,9 +2046,30 @@ fn setup_logs() -> Result<(), Box<dyn Error>> {
         .count();
     log_name.push_str(&format!("_{}", this_day_logs));
 
-    let day_file = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(log_pattern)))
-        .build(format!("logs/{}.log", log_name))
+    // the console stays readable, only the files follow the log format
+    let file_encoder = || -> Box<dyn Encode> {
+        match crawl_config.log_format {
+            LogFormat::Text => Box::new(PatternEncoder::new(log_pattern)),
+            LogFormat::Json => Box::new(JsonLineEncoder {
+                run_id: run_id.to_string(),
+            }),
+        }
+    };
+
+    // the day file rolls into logs/archives once it reaches the size limit, keeping the last archives only
+    let roller = FixedWindowRoller::builder()
+        .build(
+            &format!("logs/archives/{}.{{}}.log", log_name),
+            crawl_config.log_archives as u32,
+        )
+        .unwrap();
+    let trigger = SizeTrigger::new(crawl_config.log_max_mb as u64 * 1024 * 1024);
+    let day_file = RollingFileAppender::builder()
+        .encoder(file_encoder())
+        .build(
+            format!("logs/{}.log", log_name),
+            Box::new(CompoundPolicy::new(Box::new(trigger), Box::new(roller))),
+        )
         .unwrap();
 
     let stdout = ConsoleAppender::builder()
@@ -662,18 +2079,28 @@ fn setup_logs() -> Result<(), Box<dyn Error>> {
 
     std::fs::write("logs/latest.log", "").unwrap_or_default();
     let latest_file = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(log_pattern)))
+        .encoder(file_encoder())
         .build("logs/latest.log")
         .unwrap();
 
-    let config = Config::builder()
+    let mut root = Root::builder();
+    if !crawl_config.quiet {
+        root = root.appender("stdout");
+    }
+    let config = LogConfig::builder()
         .appender(Appender::builder().build("stdout", Box::new(stdout)))
         .appender(Appender::builder().build("day_file", Box::new(day_file)))
         .appender(Appender::builder().build("latest_file", Box::new(latest_file)))
+        .logger(Logger::builder().build(
+            SQL_LOG_TARGET,
+            if crawl_config.verbose_sql {
+                LevelFilter::Debug
+            } else {
+                LevelFilter::Info
+            },
+        ))
         .build(
-            Root::builder()
-                .appender("stdout")
-                .appender("day_file")
+            root.appender("day_file")
                 .appender("latest_file")
                 .build(LevelFilter::Info),
         )
@@ -683,3 +2110,118 @@ fn setup_logs() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anchors_give_their_link_and_displayed_text() {
+        let links = html_links(
+            r#"<p><a href="/wiki/Tour_Eiffel#Histoire" title="Tour Eiffel">la <b>tour</b> Eiffel</a>
+et <a href="/wiki/Paris" title="Paris">Paris</a>
+<a href="/wiki/Fichier:Tour.jpg" class="mw-file-description"><img src="tour.jpg" /></a></p>"#,
+        );
+        assert_eq!(
+            links,
+            HashSet::from([
+                ("tour_eiffel".to_string(), "la tour Eiffel".to_string()),
+                ("paris".to_string(), "Paris".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a mysql server in WIKICRAWL_TEST_DATABASE_URL"]
+    fn reused_alias_points_to_the_new_page() {
+        let pool = test_pool("reused_alias");
+        let mut connection = pool.get_conn().unwrap();
+        let mut last_query = String::new();
+        // the slug of a page is reused by another one after a move
+        for (id, title) in [(1, "usa"), (2, "états-unis")] {
+            let new_pages = HashMap::from([(
+                "usa".to_string(),
+                Page {
+                    id,
+                    title: title.to_string(),
+                },
+            )]);
+            save_new_pages(&mut last_query, &mut connection, &new_pages, 100).unwrap();
+        }
+        let ids: Vec<usize> = connection
+            .query("SELECT id FROM Alias WHERE alias = 'usa';")
+            .unwrap();
+        assert_eq!(ids, vec![2]);
+    }
+
+    #[test]
+    #[ignore = "needs a mysql server in WIKICRAWL_TEST_DATABASE_URL"]
+    fn explored_page_is_saved_with_its_links_and_categories() {
+        let pool = test_pool("explored_page");
+        let mut connection = pool.get_conn().unwrap();
+        connection
+            .query_drop("INSERT INTO Pages (id, title) VALUES (1, 'france'), (2, 'paris');")
+            .unwrap();
+        let page = Page {
+            id: 1,
+            title: "france".to_string(),
+        };
+        // two anchors to paris, a new page and a link that couldn't be resolved
+        let explored_page = ExploredPage {
+            links: vec![
+                ("paris".to_string(), "Paris".to_string()),
+                ("paris".to_string(), "la capitale".to_string()),
+                ("tour_eiffel".to_string(), "Tour Eiffel".to_string()),
+                ("inconnu".to_string(), "Inconnu".to_string()),
+            ],
+            categories: vec!["Pays d'Europe".to_string()],
+        };
+        let old_pages = HashMap::from([(
+            "paris".to_string(),
+            Page {
+                id: 2,
+                title: "paris".to_string(),
+            },
+        )]);
+        let new_pages = HashMap::from([(
+            "tour_eiffel".to_string(),
+            Page {
+                id: 3,
+                title: "Tour Eiffel".to_string(),
+            },
+        )]);
+
+        let mut last_query = String::new();
+        let mut transaction = connection.start_transaction(TxOpts::default()).unwrap();
+        save_new_pages(&mut last_query, &mut transaction, &new_pages, 100).unwrap();
+        let saved_links = save_explored_page(
+            &mut last_query,
+            &mut transaction,
+            &page,
+            &explored_page,
+            |link| old_pages.get(link).or(new_pages.get(link)),
+            true,
+            100,
+        )
+        .unwrap();
+        transaction.commit().unwrap();
+
+        assert_eq!(saved_links, 2);
+        let links: Vec<(usize, usize, usize)> = connection
+            .query("SELECT linker, linked, weight FROM Links ORDER BY linked;")
+            .unwrap();
+        assert_eq!(links, vec![(1, 2, 2), (1, 3, 1)]);
+        let titles: Vec<String> = connection
+            .query("SELECT title FROM Pages ORDER BY id;")
+            .unwrap();
+        assert_eq!(titles, vec!["france", "paris", "tour eiffel"]);
+        let categories: Vec<String> = connection
+            .query("SELECT category FROM Categories;")
+            .unwrap();
+        assert_eq!(categories, vec!["pays d'europe"]);
+        let explored: Option<(bool, usize)> = connection
+            .query_first("SELECT explored, degree FROM Pages WHERE id = 1;")
+            .unwrap();
+        assert_eq!(explored, Some((true, 2)));
+    }
+}
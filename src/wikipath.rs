@@ -1,221 +1,1133 @@
-use lib::*;
+use crate::schema::ensure_schema;
+use crate::wikicrawl::is_connection_lost;
+use crate::*;
 
-use mysql::{prelude::Queryable, PooledConn};
+use mysql::{
+    params,
+    prelude::{FromRow, Queryable},
+    Pool, PooledConn,
+};
+use serde::Serialize;
 use std::{
-    collections::HashMap,
-    io::{stdin, stdout, Write},
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    io::{stdin, IsTerminal},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
 };
 
-pub async fn setup_wikipath(connection: &mut PooledConn) {
-    let start_page = get_page(
-        connection,
-        "\nFrom which page do you want to start ? (enter page url or title) \n-> ",
-    )
-    .await;
-    println!("start Page {}", start_page);
+const MAX_RECONNECTIONS: usize = 3;
+const SEARCH_RESULTS: usize = 20;
+// pages expanded by each query of the heuristic search, few so that the best ones go first
+const HEURISTIC_CHUNK: usize = 64;
 
-    let end_page = get_page(
-        connection,
-        "\nTo which page do you want to go ? (enter page url or title) \n-> ",
+// the reached end page and the pages of the path, each with the link that leads to the next one
+pub type FoundPath = (Page, Vec<(Page, String)>);
+
+// how setup_wikipath searches, set by the flags of the command line. explored_only only follows the
+// links to explored pages, so that every page of the path has its own links in the database
+#[derive(Debug, Clone, Copy)]
+pub struct SearchOptions {
+    pub max_paths: usize,
+    pub heuristic: bool,
+    pub explored_only: bool,
+    // connections querying the chunks of a depth at once, 1 querying them one after the other
+    pub workers: usize,
+    // pages whose links are read by each query
+    pub chunk_size: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PathStep {
+    pub id: usize,
+    pub title: String,
+    pub via_link: String,
+}
+
+#[derive(Debug)]
+pub enum WikipathError {
+    Page(ExtractError),
+    Mysql(mysql::Error),
+    // the path is backtracked from the reached page, as (id, link to the previous id)
+    MissingPage {
+        id: usize,
+        path: Vec<(usize, String)>,
+    },
+    NoEndPage,
+    NoPath,
+}
+
+impl std::fmt::Display for WikipathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WikipathError::Page(error) => write!(f, "couldn't find the page: {}", error),
+            WikipathError::Mysql(error) => write!(f, "{}", error),
+            WikipathError::MissingPage { id, path } => write!(
+                f,
+                "page {} is missing from the database, path found so far: {}",
+                id,
+                path.iter()
+                    .map(|(id, link)| format!("{} <- \"{}\"", id, link))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            ),
+            WikipathError::NoEndPage => write!(f, "no end page given"),
+            WikipathError::NoPath => write!(f, "no path found"),
+        }
+    }
+}
+
+impl std::error::Error for WikipathError {}
+
+impl From<ExtractError> for WikipathError {
+    fn from(error: ExtractError) -> Self {
+        WikipathError::Page(error)
+    }
+}
+
+impl From<mysql::Error> for WikipathError {
+    fn from(error: mysql::Error) -> Self {
+        WikipathError::Mysql(error)
+    }
+}
+
+// the pages are given like at the prompt of setup_wikipath, as titles or urls,
+// and the path goes to the first of the end pages it reaches
+pub async fn find_path(
+    pool: &Pool,
+    config: &Config,
+    start: &str,
+    ends: &[&str],
+) -> Result<FoundPath, WikipathError> {
+    let mut paths = find_paths(pool, config, start, ends, 1).await?;
+    Ok(paths.remove(0))
+}
+
+// every shortest path to the end pages reached first, at most max_paths of them
+pub async fn find_paths(
+    pool: &Pool,
+    config: &Config,
+    start: &str,
+    ends: &[&str],
+    max_paths: usize,
+) -> Result<Vec<FoundPath>, WikipathError> {
+    let mut connection = pool.get_conn()?;
+    ensure_schema(&mut connection)?;
+    let start_page = get_page(&mut connection, &config.wiki, start, false).await?;
+    let mut end_pages = Vec::new();
+    for end in ends {
+        end_pages.push(get_page(&mut connection, &config.wiki, end, false).await?);
+    }
+    let mut last_query = String::new();
+    wikipath(
+        &mut last_query,
+        pool,
+        &mut connection,
+        start_page,
+        &end_pages,
+        SearchOptions {
+            max_paths,
+            heuristic: false,
+            explored_only: false,
+            workers: config.crawl.search_workers,
+            chunk_size: config.crawl.path_chunk,
+        },
     )
-    .await;
-    println!("end Page {}", end_page);
+}
+
+// progress and prompts are written to stderr so that stdout only carries the path
+pub async fn setup_wikipath(
+    pool: &Pool,
+    connection: &mut PooledConn,
+    wiki_config: &WikiConfig,
+    (start, end): (Option<&str>, Option<&str>),
+    json_output: bool,
+    options: SearchOptions,
+) -> Result<(), WikipathError> {
+    ensure_schema(connection)?;
+
+    let start_input = match start {
+        Some(start) => start.to_string(),
+        None => ask("\nFrom which page do you want to start ? (enter page url or title) \n-> "),
+    };
+    let start_page = get_page(connection, wiki_config, &start_input, true).await?;
+    eprintln!("start Page {}", start_page);
+
+    let mut end_pages: Vec<Page> = Vec::new();
+    if let Some(end) = end {
+        let page = get_page(connection, wiki_config, end, true).await?;
+        eprintln!("end Page {}", page);
+        end_pages.push(page);
+    }
+    while end.is_none() {
+        let end_input = ask(
+            "\nTo which page do you want to go ? (enter page url or title, or nothing to start searching) \n-> ",
+        );
+        if end_input.is_empty() {
+            break;
+        }
+        match get_page(connection, wiki_config, &end_input, true).await {
+            Ok(page) => {
+                eprintln!("end Page {}", page);
+                end_pages.push(page);
+            }
+            Err(error) => eprintln!("Error: {}", error),
+        }
+    }
+    if end_pages.is_empty() {
+        return Err(WikipathError::NoEndPage);
+    }
 
     let mut last_query = String::new();
-    let result = wikipath(&mut last_query, connection, start_page, end_page);
-    if result.is_err() {
-        println!("WIKICRAWL CRASHED WITH LAST QUERY BEING \n{}", last_query);
-        println!("Error: {:?}", result.unwrap_err());
-        return;
+    let result = if options.heuristic {
+        wikipath_heuristic(
+            &mut last_query,
+            pool,
+            connection,
+            start_page,
+            &end_pages,
+            options,
+        )
     } else {
-        let pages = result.unwrap();
-        println!(
-            "The smallest path is: \n{{\n{}\n}}",
-            pages
-                .into_iter()
-                .map(|(page, link)| format!("->  \"{}\" Page: {}", link, page))
-                .collect::<Vec<String>>()
-                .join("\n")
-        );
+        wikipath(
+            &mut last_query,
+            pool,
+            connection,
+            start_page,
+            &end_pages,
+            options,
+        )
+    };
+    let paths = match result {
+        Ok(paths) => paths,
+        Err(error) => {
+            eprintln!("WIKICRAWL CRASHED WITH LAST QUERY BEING \n{}", last_query);
+            return Err(error);
+        }
+    };
+    // one json line per path
+    for (reached_page, pages) in paths {
+        if json_output {
+            println!("{}", serde_json::to_string(&path_steps(pages)).unwrap());
+        } else {
+            println!(
+                "The smallest path reaches Page {} and is: \n{{\n{}\n}}",
+                reached_page,
+                pages
+                    .into_iter()
+                    .map(|(page, link)| format!("->  \"{}\" Page: {}", link, page))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            );
+        }
     }
+    Ok(())
 }
 
-async fn get_page(connection: &mut PooledConn, request_message: &str) -> Page {
-    let mut user_input = String::new();
-    loop {
-        print!("{}", request_message);
-        stdout().flush().unwrap();
-        user_input.clear();
-        stdin().read_line(&mut user_input).unwrap();
-        let mut page_title = {
-            if user_input.starts_with("http") {
-                let temp = user_input.split("wiki/").last().unwrap();
-                if temp.starts_with("Spécial:Recherche/") {
-                    temp.split_at(19).1
-                } else {
-                    temp
-                }
+// counts the pages reaching the target within the given number of links, depth by depth,
+// and lists them when list_pages is set
+pub async fn setup_ancestors(
+    pool: &Pool,
+    connection: &mut PooledConn,
+    wiki_config: &WikiConfig,
+    (target, max_depth): (Option<&String>, Option<&String>),
+    list_pages: bool,
+    chunk_size: usize,
+) {
+    if let Err(error) = ensure_schema(connection) {
+        eprintln!("Couldn't create the database schema: {}", error);
+        return;
+    }
+
+    let target = match target {
+        Some(target) => target.clone(),
+        None => ask("\nWhich page do you want to reach ? (enter page url or title) \n-> "),
+    };
+    let target_page = match get_page(connection, wiki_config, target.trim(), true).await {
+        Ok(page) => page,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            return;
+        }
+    };
+    eprintln!("target Page {}", target_page);
+    let max_depth = match max_depth {
+        Some(max_depth) => max_depth.clone(),
+        None => ask("\nWithin how many links ? \n-> "),
+    };
+    let max_depth = match max_depth.trim().parse::<usize>() {
+        Ok(max_depth) if max_depth > 0 => max_depth,
+        _ => {
+            eprintln!("Invalid number of links \"{}\"", max_depth.trim());
+            return;
+        }
+    };
+
+    let mut last_query = String::new();
+    let result = ancestors(
+        &mut last_query,
+        pool,
+        connection,
+        &target_page,
+        max_depth,
+        chunk_size,
+    )
+    .and_then(|depths| {
+        let mut pages = Vec::new();
+        for depth_pages_id in depths {
+            let depth_pages = if list_pages {
+                get_pages(
+                    &mut last_query,
+                    pool,
+                    connection,
+                    &depth_pages_id,
+                    chunk_size,
+                )?
             } else {
-                user_input.as_str()
+                Vec::new()
+            };
+            pages.push((depth_pages_id.len(), depth_pages));
+        }
+        Ok(pages)
+    });
+    let depths = match result {
+        Ok(depths) => depths,
+        Err(error) => {
+            eprintln!("WIKICRAWL CRASHED WITH LAST QUERY BEING \n{}", last_query);
+            eprintln!("Error: {}", error);
+            return;
+        }
+    };
+    let mut total = 0;
+    for (depth, (count, pages)) in depths.into_iter().enumerate() {
+        total += count;
+        println!("depth {}: {} pages", depth + 1, count);
+        for page in pages {
+            println!("    Page {}", page);
+        }
+    }
+    println!(
+        "{} pages reach Page {} within {} links",
+        total, target_page, max_depth
+    );
+}
+
+// lists the pages whose title or one of the links to them starts with the prefix, the most linked first,
+// to pick the right page among the homonyms before a search
+pub fn setup_search(connection: &mut PooledConn, prefix: Option<&String>) {
+    if let Err(error) = ensure_schema(connection) {
+        eprintln!("Couldn't create the database schema: {}", error);
+        return;
+    }
+
+    let prefix = match prefix {
+        Some(prefix) => prefix.clone(),
+        None => ask("\nWhich title do you want to search ? (enter the start of the title) \n-> "),
+    };
+    // titles and aliases are stored lowercased, the titles with spaces and the aliases like the links
+    let prefix = normalize_title(prefix.trim()).to_lowercase();
+    let escaped = prefix
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let query = format!(
+        "SELECT id, title FROM (
+    SELECT id, title, degree FROM Pages WHERE title LIKE :title
+    UNION
+    SELECT Pages.id, Pages.title, Pages.degree FROM Alias JOIN Pages ON Pages.id = Alias.id WHERE Alias.alias LIKE :alias
+) AS matches
+ORDER BY degree IS NULL, degree DESC, title
+LIMIT {};",
+        SEARCH_RESULTS
+    );
+    let result = timed(&query, || {
+        connection.exec_map(
+            &query,
+            params! {
+                "title" => format!("{}%", escaped.replace("\\_", " ")),
+                "alias" => format!("{}%", escaped.replace(' ', "\\_")),
+            },
+            |(id, title): (usize, String)| Page { id, title },
+        )
+    });
+    match result {
+        Ok(pages) if pages.is_empty() => println!("No page starts with \"{}\"", prefix),
+        Ok(pages) => {
+            for page in pages {
+                println!("Page {}", page);
             }
         }
-        .to_ascii_lowercase();
-        page_title.pop();
+        Err(error) => eprintln!("Error: {}", error),
+    }
+}
+
+pub fn path_steps(path: Vec<(Page, String)>) -> Vec<PathStep> {
+    path.into_iter()
+        .map(|(page, via_link)| PathStep {
+            id: page.id,
+            title: page.title,
+            via_link,
+        })
+        .collect()
+}
+
+// several pages matching the input are listed to choose from when interactive and stdin is a terminal,
+// and returned as an Ambiguous error otherwise
+pub(crate) async fn get_page(
+    connection: &mut PooledConn,
+    wiki_config: &WikiConfig,
+    user_input: &str,
+    interactive: bool,
+) -> Result<Page, WikipathError> {
+    let user_input = normalize_title(user_input);
+    let page_title = {
+        if user_input.starts_with("http") {
+            let temp = user_input.split("wiki/").last().unwrap();
+            temp.strip_prefix(&format!("{}/", wiki_config.search_page))
+                .unwrap_or(temp)
+        } else {
+            user_input.as_str()
+        }
+    };
 
-        let formatted_link = format_link_for_mysql(&page_title);
-        let query = format!(
-            "
+    // the title in the case of wikipedia is matched exactly first, wikipedia telling "SARL" from "Sarl",
+    // the older rows without it being found by the lowercased search below
+    eprintln!("querying database");
+    let title = page_title.replace('_', " ");
+    let query = "SELECT id, title FROM Pages WHERE BINARY original_title = :title;";
+    let exact_page = timed(query, || {
+        connection.exec_first(query, params! { "title" => &title })
+    })?
+    .map(|(id, title): (usize, String)| Page { id, title });
+    if let Some(page) = exact_page {
+        return Ok(page);
+    }
+
+    // titles and aliases are stored lowercased, the link is bound instead of escaped into the query
+    let link = page_title.to_lowercase();
+    let query = "
 SELECT id, title
 FROM ( 
-    ( 
-        SELECT Pages.id, Pages.title, Alias.alias 
-        FROM Pages 
-        JOIN Alias ON Alias.id = Pages.id 
-        WHERE MATCH(title) AGAINST ('{formatted_link}' IN BOOLEAN MODE) 
-    ) 
-    UNION 
-    ( 
-        SELECT Pages.id, Pages.title, Alias.alias 
-        FROM Pages 
-        JOIN Alias ON Alias.id = Pages.id 
-        WHERE MATCH(alias) AGAINST ('{formatted_link}' IN BOOLEAN MODE) 
-    ) 
+( 
+    SELECT Pages.id, Pages.title, Alias.alias 
+    FROM Pages 
+    JOIN Alias ON Alias.id = Pages.id 
+    WHERE MATCH(title) AGAINST (:link IN BOOLEAN MODE) 
+) 
+UNION 
+( 
+    SELECT Pages.id, Pages.title, Alias.alias 
+    FROM Pages 
+    JOIN Alias ON Alias.id = Pages.id 
+    WHERE MATCH(alias) AGAINST (:link IN BOOLEAN MODE) 
+) 
 ) AS result 
-WHERE title = '{formatted_link}' OR alias = '{formatted_link}';"
-        );
+WHERE title = :link OR alias = :link;";
 
-        println!("querying database");
-        let page = connection
-            .query_map(query, |(id, title): (usize, String)| Page { id, title })
-            .unwrap_or(Vec::new());
+    let mut pages = timed(query, || {
+        connection.exec_map(
+            query,
+            params! { "link" => &link },
+            |(id, title): (usize, String)| Page { id, title },
+        )
+    })?;
+    // a page matching by several of its aliases comes once per alias
+    let mut seen_pages_id = HashSet::new();
+    pages.retain(|page| seen_pages_id.insert(page.id));
 
-        let page = if page.is_empty() {
-            println!("no pages found in the database, querying wikipedia");
-            extract_link_info_api(&page_title).await
-        } else {
-            page.first().unwrap().to_owned()
-        };
+    match pages.len() {
+        0 => {
+            eprintln!("no pages found in the database, querying wikipedia");
+            let page = extract_link_info_api(page_title, wiki_config, &*CLIENT).await?;
+            if wiki_config.save_lookups {
+                if let Err(error) = save_looked_up_page(connection, &page, &link) {
+                    eprintln!("Couldn't save Page {} to the database: {}", page, error);
+                }
+            }
+            Ok(page)
+        }
+        1 => Ok(pages.remove(0)),
+        _ if interactive && stdin().is_terminal() => Ok(choose_page(pages)),
+        _ => Err(ExtractError::Ambiguous(pages).into()),
+    }
+}
+
+// the page is saved like the crawl saves the new pages, an existing row being left as it is
+fn save_looked_up_page(
+    connection: &mut PooledConn,
+    page: &Page,
+    link: &str,
+) -> Result<(), mysql::Error> {
+    let query = "INSERT IGNORE INTO Pages (id, title, original_title, created_at) VALUES (:id, :title, :original_title, NOW());";
+    timed(query, || {
+        connection.exec_drop(
+            query,
+            params! {
+                "id" => page.id,
+                "title" => page.title.to_lowercase(),
+                "original_title" => &page.title,
+            },
+        )
+    })?;
+    let query = "INSERT INTO Alias (alias, id) VALUES (:alias, :id) ON DUPLICATE KEY UPDATE id = VALUES(id);";
+    timed(query, || {
+        connection.exec_drop(query, params! { "alias" => link, "id" => page.id })
+    })
+}
 
-        return page;
+fn choose_page(mut pages: Vec<Page>) -> Page {
+    eprintln!("\nseveral pages match:");
+    for (i, page) in pages.iter().enumerate() {
+        eprintln!("{}: Page {}", i + 1, page);
+    }
+    loop {
+        match ask("Which one do you mean ? (enter its number) \n-> ").parse::<usize>() {
+            Ok(choice) if (1..=pages.len()).contains(&choice) => return pages.remove(choice - 1),
+            _ => eprintln!("Please enter a number between 1 and {}.", pages.len()),
+        }
     }
 }
 
 fn wikipath(
     last_query: &mut String,
+    pool: &Pool,
     connection: &mut PooledConn,
     start_page: Page,
-    end_page: Page,
-) -> Result<Vec<(Page, String)>, mysql::Error> {
+    end_pages: &[Page],
+    options: SearchOptions,
+) -> Result<Vec<FoundPath>, WikipathError> {
+    if let Some(end_page) = end_pages.iter().find(|page| page.id == start_page.id) {
+        return Ok(vec![(end_page.clone(), vec![(start_page, String::new())])]);
+    }
+    let end_pages_id = end_pages
+        .iter()
+        .map(|page| page.id)
+        .collect::<HashSet<usize>>();
+    let mut reached_pages_id: Vec<usize> = Vec::new();
+    let (join, condition) = traversal_filter(options.explored_only, &end_pages_id);
+
     // exploring the database
-    // we only collect the first time where a page is linked to another page
-    // because we are looking for the shortest path, since we are exploring the database depth by depth,
-    // we can be sure that the first time we find a page, it's one of the shortest path to it
-    let mut is_linked_first: HashMap<usize, (usize, String)> = HashMap::new();
+    // since we are exploring the database depth by depth, the linkers of a page at the depth where
+    // it is first found are the ends of all the shortest paths to it, a single path only needs the first one
+    let mut predecessors: HashMap<usize, Vec<(usize, String)>> = HashMap::new();
+    let mut depth_of: HashMap<usize, usize> = HashMap::from([(start_page.id, 0)]);
     let mut exploring_pages_id: Vec<usize> = vec![start_page.id];
 
-    'truc: for depth in 0.. {
-        println!("exploring depth {}", depth);
+    for depth in 0.. {
+        eprintln!("exploring depth {}", depth);
+        let queries = exploring_pages_id
+            .chunks(options.chunk_size)
+            .map(|exploring_chunk| {
+                (
+                    exploring_chunk.len(),
+                    format!(
+                        "SELECT Links.linker, Links.linked, Links.display FROM Links{} WHERE Links.linker IN ({}){};",
+                        join,
+                        exploring_chunk
+                            .iter()
+                            .map(|id| id.to_string())
+                            .collect::<Vec<String>>()
+                            .join(","),
+                        condition
+                    ),
+                )
+            })
+            .collect::<Vec<(usize, String)>>();
+
+        let mut next_exploring_pages_id: Vec<usize> = Vec::new();
+        let mut found_end_page = None;
+        let state = Mutex::new((
+            &mut depth_of,
+            &mut predecessors,
+            &mut next_exploring_pages_id,
+            &mut found_end_page,
+        ));
+        query_concurrently(
+            last_query,
+            pool,
+            connection,
+            &queries,
+            options.workers,
+            |(linker, linked, displayed_link): (usize, usize, String)| {
+                let mut state = state.lock().unwrap();
+                let (depth_of, predecessors, next_exploring_pages_id, found_end_page) = &mut *state;
+                // another chunk may have found the end page while this one was streaming
+                if found_end_page.is_some() {
+                    return false;
+                }
+                match depth_of.get(&linked) {
+                    None => {
+                        depth_of.insert(linked, depth + 1);
+                        next_exploring_pages_id.push(linked);
+                        predecessors.insert(linked, vec![(linker, displayed_link)]);
+                    }
+                    Some(linked_depth) if *linked_depth == depth + 1 && options.max_paths > 1 => {
+                        let linkers = predecessors.get_mut(&linked).unwrap();
+                        if !linkers.iter().any(|(known, _)| *known == linker) {
+                            linkers.push((linker, displayed_link));
+                        }
+                    }
+                    _ => {}
+                }
+                // a single path stops at the first end page found, all of them need the whole depth
+                if options.max_paths == 1 && end_pages_id.contains(&linked) {
+                    **found_end_page = Some(linked);
+                    return false;
+                }
+                true
+            },
+        )?;
+        eprintln!();
+        if let Some(linked) = found_end_page {
+            eprintln!("found end page");
+            reached_pages_id.push(linked);
+            break;
+        }
+
+        reached_pages_id.extend(
+            end_pages
+                .iter()
+                .map(|page| page.id)
+                .filter(|id| depth_of.get(id) == Some(&(depth + 1))),
+        );
+        if !reached_pages_id.is_empty() {
+            eprintln!("found end page");
+            break;
+        }
+
+        if next_exploring_pages_id.is_empty() {
+            return Err(WikipathError::NoPath);
+        }
+
+        exploring_pages_id.clear();
+        exploring_pages_id.extend(next_exploring_pages_id);
+    }
+
+    build_paths(
+        last_query,
+        pool,
+        connection,
+        &start_page,
+        &reached_pages_id,
+        &predecessors,
+        options,
+    )
+}
+
+// greedy best first search, the linked pages sharing the most categories with the end pages are
+// expanded first, so the path is found with fewer queries but isn't always one of the shortest
+fn wikipath_heuristic(
+    last_query: &mut String,
+    pool: &Pool,
+    connection: &mut PooledConn,
+    start_page: Page,
+    end_pages: &[Page],
+    options: SearchOptions,
+) -> Result<Vec<FoundPath>, WikipathError> {
+    let end_pages_id = end_pages
+        .iter()
+        .map(|page| page.id)
+        .collect::<HashSet<usize>>();
+    let (join, condition) = traversal_filter(options.explored_only, &end_pages_id);
+    last_query.clear();
+    last_query.push_str(&format!(
+        "SELECT DISTINCT category FROM Categories WHERE id IN ({});",
+        end_pages_id
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    ));
+    let end_categories: Vec<String> = query_reconnecting(last_query, pool, connection)?;
+    if end_categories.is_empty() || end_pages_id.contains(&start_page.id) {
+        eprintln!("no categories for the end pages, falling back to the breadth first search");
+        return wikipath(
+            last_query,
+            pool,
+            connection,
+            start_page,
+            end_pages,
+            SearchOptions {
+                max_paths: 1,
+                ..options
+            },
+        );
+    }
+    // the categories are read again by a subquery instead of being written into the query
+    let end_categories = format!(
+        "SELECT category FROM Categories WHERE id IN ({})",
+        end_pages_id
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    );
+
+    // (shared categories, depth, id), the shallowest pages go first among the same score
+    let mut frontier: BinaryHeap<(usize, Reverse<usize>, usize)> =
+        BinaryHeap::from([(0, Reverse(0), start_page.id)]);
+    let mut predecessors: HashMap<usize, Vec<(usize, String)>> = HashMap::new();
+    let mut seen: HashSet<usize> = HashSet::from([start_page.id]);
+    let mut explored = 0;
+    let mut reached_page_id = None;
+
+    eprintln!("exploring the pages sharing the most categories with the end pages first");
+    while reached_page_id.is_none() && !frontier.is_empty() {
+        let chunk = (0..HEURISTIC_CHUNK)
+            .map_while(|_| frontier.pop())
+            .map(|(_, Reverse(depth), id)| (id, depth))
+            .collect::<HashMap<usize, usize>>();
+        explored += chunk.len();
+        last_query.clear();
+        last_query.push_str(&format!(
+            "SELECT Links.linker, Links.linked, Links.display, COUNT(Categories.category) FROM Links{} LEFT JOIN Categories ON Categories.id = Links.linked AND Categories.category IN ({}) WHERE Links.linker IN ({}){} GROUP BY Links.linker, Links.linked, Links.display;",
+            join,
+            end_categories,
+            chunk
+                .keys()
+                .map(|id| id.to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+            condition
+        ));
+        let pages: Vec<(usize, usize, String, usize)> =
+            query_reconnecting(last_query, pool, connection)?;
+
+        for (linker, linked, displayed_link, score) in pages {
+            if !seen.insert(linked) {
+                continue;
+            }
+            predecessors.insert(linked, vec![(linker, displayed_link)]);
+            if end_pages_id.contains(&linked) {
+                reached_page_id = Some(linked);
+                break;
+            }
+            frontier.push((score, Reverse(chunk[&linker] + 1), linked));
+        }
+        eprint!(
+            "\rexplored {} pages, {} in the frontier",
+            explored,
+            frontier.len()
+        );
+    }
+    eprintln!();
+
+    match reached_page_id {
+        Some(reached_page_id) => {
+            eprintln!("found end page");
+            build_paths(
+                last_query,
+                pool,
+                connection,
+                &start_page,
+                &[reached_page_id],
+                &predecessors,
+                SearchOptions {
+                    max_paths: 1,
+                    ..options
+                },
+            )
+        }
+        None => Err(WikipathError::NoPath),
+    }
+}
+
+// the pages linking to the target, then to these pages and so on up to max_depth, each page at the
+// depth of its shortest path to the target. stops early when a depth finds no new page
+fn ancestors(
+    last_query: &mut String,
+    pool: &Pool,
+    connection: &mut PooledConn,
+    target_page: &Page,
+    max_depth: usize,
+    chunk_size: usize,
+) -> Result<Vec<Vec<usize>>, WikipathError> {
+    let mut depths: Vec<Vec<usize>> = Vec::new();
+    let mut reached_pages_id: HashSet<usize> = HashSet::from([target_page.id]);
+    let mut exploring_pages_id: Vec<usize> = vec![target_page.id];
+
+    for depth in 1..=max_depth {
+        eprintln!("exploring depth {}", depth);
         let mut next_exploring_pages_id: Vec<usize> = Vec::new();
 
         let mut i = 0;
-        for exploring_chunk in exploring_pages_id.chunks(8192) {
+        for exploring_chunk in exploring_pages_id.chunks(chunk_size) {
             i += exploring_chunk.len();
             last_query.clear();
             last_query.push_str(&format!(
-                "SELECT * FROM Links WHERE linker IN ({});",
+                "SELECT Links.linker FROM Links WHERE Links.linked IN ({});",
                 exploring_chunk
                     .iter()
                     .map(|id| id.to_string())
                     .collect::<Vec<String>>()
                     .join(",")
             ));
-
-            let pages = connection.query_map(
-                &last_query,
-                |(linker, linked, displayed_link): (usize, usize, String)| {
-                    (linker, linked, displayed_link)
-                },
-            )?;
-
-            pages.iter().for_each(|(linker, linked, displayed_link)| {
-                if !is_linked_first.contains_key(&linked) {
-                    next_exploring_pages_id.push(*linked);
-                    is_linked_first.insert(*linked, (*linker, displayed_link.clone()));
+            stream_reconnecting(last_query, pool, connection, |linker: usize| {
+                if reached_pages_id.insert(linker) {
+                    next_exploring_pages_id.push(linker);
                 }
-            });
-
-            if pages
-                .iter()
-                .find(|(_, linked, _displayed_link)| end_page.id.eq(linked))
-                .is_some()
-            {
-                println!("found end page");
-                break 'truc;
-            }
-            print!(
+                true
+            })?;
+            eprint!(
                 "\rexplored {}/{} ({}%)",
                 i,
                 exploring_pages_id.len(),
                 i * 100 / exploring_pages_id.len()
             );
-            stdout().flush().unwrap();
         }
-        println!();
+        eprintln!();
 
         if next_exploring_pages_id.is_empty() {
-            return Err(mysql::Error::MySqlError(mysql::MySqlError {
-                code: 0,
-                state: "".to_string(),
-                message: "No path found".to_string(),
-            }));
+            break;
         }
+        depths.push(next_exploring_pages_id.clone());
+        exploring_pages_id = next_exploring_pages_id;
+    }
+    Ok(depths)
+}
 
-        exploring_pages_id.clear();
-        exploring_pages_id.extend(next_exploring_pages_id.into_iter());
+fn get_pages(
+    last_query: &mut String,
+    pool: &Pool,
+    connection: &mut PooledConn,
+    pages_id: &[usize],
+    chunk_size: usize,
+) -> Result<Vec<Page>, WikipathError> {
+    let mut pages = Vec::new();
+    for chunk in pages_id.chunks(chunk_size) {
+        last_query.clear();
+        last_query.push_str(&format!(
+            "SELECT id, title FROM Pages WHERE id IN ({}) ORDER BY title;",
+            chunk
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        ));
+        pages.extend(
+            query_reconnecting(last_query, pool, connection)?
+                .into_iter()
+                .map(|(id, title): (usize, String)| Page { id, title }),
+        );
     }
+    Ok(pages)
+}
 
-    // backtrack the smallest path
-    print!("backtracking the smallest path \n{}", end_page.id);
-    let mut path: Vec<(usize, String)> = vec![(end_page.id, String::new())];
-    while let Some((last_page, _last_link)) = path.last() {
-        let (next_page, next_link) = is_linked_first.get(last_page).unwrap();
-        path.push((*next_page, next_link.clone()));
-        print!(" -> {} by \"{}\"", next_page, next_link);
-        if next_page.eq(&start_page.id) {
-            break;
+// the join and condition appended to the queries of the links, the links to unexplored pages
+// are still followed when they reach an end page
+fn traversal_filter(explored_only: bool, end_pages_id: &HashSet<usize>) -> (&'static str, String) {
+    if !explored_only {
+        return ("", String::new());
+    }
+    (
+        " JOIN Pages ON Pages.id = Links.linked",
+        format!(
+            " AND (Pages.explored = TRUE OR Links.linked IN ({}))",
+            end_pages_id
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        ),
+    )
+}
+
+// the search state is in memory, so a lost connection only costs the current query
+fn query_reconnecting<T: FromRow>(
+    last_query: &str,
+    pool: &Pool,
+    connection: &mut PooledConn,
+) -> Result<Vec<T>, WikipathError> {
+    let mut reconnections = 0;
+    loop {
+        match timed(last_query, || connection.query(last_query)) {
+            Ok(rows) => return Ok(rows),
+            Err(error) if is_connection_lost(&error) && reconnections < MAX_RECONNECTIONS => {
+                eprintln!("\nconnection to the database lost, getting a new one from the pool");
+                reconnections += 1;
+                *connection = pool.get_conn()?;
+            }
+            Err(error) => return Err(error.into()),
         }
     }
-    println!();
+}
 
-    // convert the path from id to title
-    last_query.clear();
-    last_query.push_str(&format!(
-        "SELECT id,title from Pages where id IN ({});",
-        path.iter()
-            .map(|(id, _link)| id.to_string())
-            .collect::<Vec<String>>()
-            .join(",")
-    ));
+// the queries are shared by the workers, the first one using the given connection and the others their own
+// from the pool, so on_row gets the rows of several queries at once and has to lock what it changes.
+// once on_row returns false the remaining queries are skipped, the ones running stop at their next row
+fn query_concurrently<T: FromRow + Send>(
+    last_query: &mut String,
+    pool: &Pool,
+    connection: &mut PooledConn,
+    queries: &[(usize, String)],
+    workers: usize,
+    on_row: impl Fn(T) -> bool + Sync,
+) -> Result<(), WikipathError> {
+    let total_pages = queries.iter().map(|(pages, _)| pages).sum::<usize>();
+    let next_query = AtomicUsize::new(0);
+    let explored_pages = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    let worker = |connection: &mut PooledConn| -> Result<(), (String, WikipathError)> {
+        loop {
+            let Some((pages, query)) = queries.get(next_query.fetch_add(1, Ordering::SeqCst))
+            else {
+                return Ok(());
+            };
+            if stopped.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            stream_reconnecting(query, pool, connection, |row: T| {
+                if stopped.load(Ordering::SeqCst) || !on_row(row) {
+                    stopped.store(true, Ordering::SeqCst);
+                    return false;
+                }
+                true
+            })
+            .map_err(|error| (query.clone(), error))?;
+            let explored = explored_pages.fetch_add(*pages, Ordering::SeqCst) + pages;
+            eprint!(
+                "\rexplored {}/{} ({}%)",
+                explored,
+                total_pages,
+                explored * 100 / total_pages
+            );
+        }
+    };
+
+    let result = std::thread::scope(|scope| {
+        let handles = (1..workers.min(queries.len()))
+            .map(|_| {
+                scope.spawn(|| match pool.get_conn() {
+                    Ok(mut connection) => worker(&mut connection),
+                    Err(error) => Err((String::new(), error.into())),
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut result = worker(connection);
+        for handle in handles {
+            result = result.and(handle.join().unwrap());
+        }
+        result
+    });
+    match result {
+        Ok(()) => {
+            if let Some((_, query)) = queries.last() {
+                last_query.clone_from(query);
+            }
+            Ok(())
+        }
+        Err((query, error)) => {
+            last_query.clone_from(&query);
+            Err(error)
+        }
+    }
+}
+
+// the rows go to on_row as they arrive instead of being collected, until it returns false.
+// a lost connection runs the query again from its first row, so on_row has to accept a row twice
+fn stream_reconnecting<T: FromRow>(
+    last_query: &str,
+    pool: &Pool,
+    connection: &mut PooledConn,
+    mut on_row: impl FnMut(T) -> bool,
+) -> Result<(), WikipathError> {
+    let mut reconnections = 0;
+    loop {
+        let result = timed(last_query, || {
+            connection.query_iter(last_query).and_then(|mut rows| {
+                for row in rows.by_ref() {
+                    let row = T::from_row_opt(row?)
+                        .map_err(|error| mysql::Error::FromRowError(error.0))?;
+                    if !on_row(row) {
+                        break;
+                    }
+                }
+                Ok(())
+            })
+        });
+        match result {
+            Ok(()) => return Ok(()),
+            Err(error) if is_connection_lost(&error) && reconnections < MAX_RECONNECTIONS => {
+                eprintln!("\nconnection to the database lost, getting a new one from the pool");
+                reconnections += 1;
+                *connection = pool.get_conn()?;
+            }
+            Err(error) => return Err(error.into()),
+        }
+    }
+}
+
+// at most options.max_paths paths, converted from id to title by chunks like the links are read
+fn build_paths(
+    last_query: &mut String,
+    pool: &Pool,
+    connection: &mut PooledConn,
+    start_page: &Page,
+    reached_pages_id: &[usize],
+    predecessors: &HashMap<usize, Vec<(usize, String)>>,
+    options: SearchOptions,
+) -> Result<Vec<FoundPath>, WikipathError> {
+    // backtrack the smallest paths, walking the predecessors from each reached page back to the start page
+    eprintln!("backtracking the smallest paths");
+    let mut paths: Vec<Vec<(usize, String)>> = Vec::new();
+    'backtrack: for reached_page_id in reached_pages_id.iter() {
+        let mut stack: Vec<Vec<(usize, String)>> = vec![vec![(*reached_page_id, String::new())]];
+        while let Some(path) = stack.pop() {
+            let last_page = path.last().unwrap().0;
+            if last_page == start_page.id {
+                eprintln!(
+                    "{}",
+                    path.iter()
+                        .map(|(id, link)| format!("{} by \"{}\"", id, link))
+                        .collect::<Vec<String>>()
+                        .join(" <- ")
+                );
+                paths.push(path);
+                if paths.len() >= options.max_paths {
+                    break 'backtrack;
+                }
+                continue;
+            }
+            let Some(linkers) = predecessors.get(&last_page) else {
+                return Err(WikipathError::MissingPage {
+                    id: last_page,
+                    path,
+                });
+            };
+            for (linker, link) in linkers.iter().rev() {
+                let mut next_path = path.clone();
+                next_path.push((*linker, link.clone()));
+                stack.push(next_path);
+            }
+        }
+    }
 
-    println!("converting the path from id to title");
-    let id_to_title = connection
-        .query_map(&last_query, |(id, title): (usize, String)| (id, title))?
+    // convert the paths from id to title
+    eprintln!("converting the paths from id to title");
+    let paths_id = paths
+        .iter()
+        .flatten()
+        .map(|(id, _link)| *id)
+        .collect::<HashSet<usize>>()
         .into_iter()
+        .collect::<Vec<usize>>();
+    let id_to_title = get_pages(last_query, pool, connection, &paths_id, options.chunk_size)?
+        .into_iter()
+        .map(|page| (page.id, page.title))
         .collect::<HashMap<usize, String>>();
 
-    let final_path = path
-        .into_iter()
-        .map(|(id, link)| {
-            (
-                Page {
-                    id,
-                    title: id_to_title.get(&id).unwrap().clone(),
-                },
-                link,
-            )
-        })
-        .rev()
-        .collect::<Vec<(Page, String)>>();
+    let mut final_paths = Vec::new();
+    for path in paths {
+        if let Some((id, _link)) = path
+            .iter()
+            .find(|(id, _link)| !id_to_title.contains_key(id))
+        {
+            return Err(WikipathError::MissingPage { id: *id, path });
+        }
+        // the path starts from the reached end page
+        let reached_page = Page {
+            id: path[0].0,
+            title: id_to_title[&path[0].0].clone(),
+        };
+        let final_path = path
+            .into_iter()
+            .map(|(id, link)| {
+                (
+                    Page {
+                        title: id_to_title[&id].clone(),
+                        id,
+                    },
+                    link,
+                )
+            })
+            .rev()
+            .collect::<Vec<(Page, String)>>();
+        final_paths.push((reached_page, final_path));
+    }
+    Ok(final_paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1 -> 2 -> 5 is shorter than 1 -> 3 -> 4 -> 5, and nothing links to 6
+    const FIXTURE_GRAPH: [&str; 2] = [
+        "INSERT INTO Pages (id, title, explored) VALUES (1, 'france', TRUE), (2, 'paris', TRUE), (3, 'lyon', TRUE), (4, 'rhône', TRUE), (5, 'seine', TRUE), (6, 'orphelin', FALSE);",
+        "INSERT INTO Links (linker, linked, display, weight) VALUES (1, 2, 'paris', 1), (2, 5, 'la seine', 1), (1, 3, 'lyon', 1), (3, 4, 'le rhône', 1), (4, 5, 'seine', 1);",
+    ];
 
-    Ok(final_path)
+    fn fixture_pool(name: &str) -> Pool {
+        let pool = test_pool(name);
+        let mut connection = pool.get_conn().unwrap();
+        for query in FIXTURE_GRAPH {
+            connection.query_drop(query).unwrap();
+        }
+        pool
+    }
+
+    fn search_options() -> SearchOptions {
+        SearchOptions {
+            max_paths: 1,
+            heuristic: false,
+            explored_only: false,
+            workers: 1,
+            chunk_size: 2,
+        }
+    }
+
+    fn page(id: usize, title: &str) -> Page {
+        Page {
+            id,
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a mysql server in WIKICRAWL_TEST_DATABASE_URL"]
+    fn shortest_path_is_found_with_its_links() {
+        let pool = fixture_pool("shortest_path");
+        let mut connection = pool.get_conn().unwrap();
+        let paths = wikipath(
+            &mut String::new(),
+            &pool,
+            &mut connection,
+            page(1, "france"),
+            &[page(5, "seine")],
+            search_options(),
+        )
+        .unwrap();
+        assert_eq!(paths.len(), 1);
+        let (reached_page, steps) = &paths[0];
+        assert_eq!(reached_page.id, 5);
+        assert_eq!(
+            steps
+                .iter()
+                .map(|(page, link)| (page.id, page.title.as_str(), link.as_str()))
+                .collect::<Vec<(usize, &str, &str)>>(),
+            vec![
+                (1, "france", "paris"),
+                (2, "paris", "la seine"),
+                (5, "seine", "")
+            ]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a mysql server in WIKICRAWL_TEST_DATABASE_URL"]
+    fn unreachable_page_has_no_path() {
+        let pool = fixture_pool("no_path");
+        let mut connection = pool.get_conn().unwrap();
+        let result = wikipath(
+            &mut String::new(),
+            &pool,
+            &mut connection,
+            page(1, "france"),
+            &[page(6, "orphelin")],
+            search_options(),
+        );
+        assert!(matches!(result, Err(WikipathError::NoPath)));
+    }
 }